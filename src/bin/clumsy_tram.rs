@@ -7,11 +7,20 @@
 use defmt::debug;
 use defmt_rtt as _;
 
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use panic_probe as _;
 // Provide an alias for our BSP so we can switch targets quickly.
 // Uncomment the BSP you included in Cargo.toml, the rest of the code does not need to change.
-use clumsy_stm_bot::drivers::{line_sensor::LineSensor, motor::Motor};
+use clumsy_stm_bot::{
+    control::Pid,
+    drive::DifferentialDrive,
+    drivers::{
+        hcsr04::{Hcsr04, Hcsr04Config},
+        line_sensor::{LINE_LOOP_PERIOD, LineArray, LineSensor},
+        motor::Motor,
+        range::{RangeError, RangeFilter, RangeSensor, SharedRange},
+    },
+};
 
 use embassy_executor::{InterruptExecutor, Spawner};
 use embassy_stm32::{
@@ -21,7 +30,7 @@ use embassy_stm32::{
     time::khz,
     timer::simple_pwm::{PwmPin, SimplePwm, SimplePwmChannel},
 };
-use embassy_time::{Delay, Duration, Instant, Timer};
+use embassy_time::{Duration, Instant, Timer};
 
 use embassy_stm32::{
     self as _,
@@ -29,28 +38,19 @@ use embassy_stm32::{
 };
 
 use clumsy_stm_bot as _;
-use hcsr04_async::{DistanceUnit, Hcsr04, TemperatureUnit};
 
 type LeftMotor<'a> = Motor<SimplePwmChannel<'a, TIM3>, Output<'a>, Output<'a>>;
 type RightMotor<'a> = Motor<SimplePwmChannel<'a, TIM2>, Output<'a>, Output<'a>>;
+type MyDrive<'a> = DifferentialDrive<LeftMotor<'a>, RightMotor<'a>>;
 
-struct EmbassyClock;
-
-impl hcsr04_async::Now for EmbassyClock {
-    fn now_micros(&self) -> u64 {
-        Instant::now().as_micros()
-    }
-}
 use embassy_stm32::exti::ExtiInput;
-type MySonar<'a> = Hcsr04<Output<'a>, ExtiInput<'a>, EmbassyClock, Delay>;
-
-type Distance = f64;
+type MySonar<'a> = Hcsr04<Output<'a>, ExtiInput<'a>>;
 
-type MyLineSensor<'a> = [LineSensor<Input<'a>>; 5];
+type MyLineSensor<'a> = LineArray<Input<'a>, 5>;
 
-const TEMPERATURE: f64 = 22.0;
+const TEMPERATURE: f32 = 22.0;
 
-const MINIMUM_DISTANCE: f64 = 6.0; // cm
+const MINIMUM_DISTANCE: f32 = 60.0; // mm
 
 const SPEED: f32 = 100.0;
 
@@ -61,11 +61,13 @@ const KI: f32 = 0.050;
 const KD: f32 = 100.0;
 const KA: f32 = 0.082; // reduction of the movement speed
 
-const MIDDLE: f32 = 2.0;
+const SENSOR_SPACING_MM: f32 = 10.0;
 
 const SONAR_MEASURE_CYCLE: Duration = Duration::from_millis(60);
-type MySignal = Signal<CriticalSectionRawMutex, Distance>;
-static SOME_SIGNAL: MySignal = Signal::new();
+// older ranges are not trusted, the latest failure tells whether the way is clear
+const SONAR_MAX_AGE: Duration = Duration::from_millis(3 * SONAR_MEASURE_CYCLE.as_millis());
+type MySonarRange = SharedRange<CriticalSectionRawMutex>;
+static SONAR_RANGE: MySonarRange = SharedRange::new();
 
 static EXECUTOR_MED: InterruptExecutor = InterruptExecutor::new();
 
@@ -115,41 +117,39 @@ async fn main(spawner: Spawner) {
         0.0,
         Default::default(),
     );
-    let line_sensors = [
-        LineSensor::new_invert(Input::new(p.PB0, Pull::Down)),
-        LineSensor::new(Input::new(p.PB4, Pull::Down)),
-        LineSensor::new(Input::new(p.PB5, Pull::Down)),
-        LineSensor::new(Input::new(p.PB3, Pull::Down)),
-        LineSensor::new_invert(Input::new(p.PA4, Pull::Down)),
-    ];
+    let line_sensors = LineArray::new(
+        [
+            LineSensor::new_invert(Input::new(p.PB0, Pull::Down)),
+            LineSensor::new(Input::new(p.PB4, Pull::Down)),
+            LineSensor::new(Input::new(p.PB5, Pull::Down)),
+            LineSensor::new(Input::new(p.PB3, Pull::Down)),
+            LineSensor::new_invert(Input::new(p.PA4, Pull::Down)),
+        ],
+        SENSOR_SPACING_MM,
+    );
 
     let trigger = Output::new(p.PC0, Level::Low, Speed::High);
     let echo = ExtiInput::new(p.PC1, p.EXTI1, Pull::None);
 
-    let config = hcsr04_async::Config {
-        distance_unit: DistanceUnit::Centimeters,
-        temperature_unit: TemperatureUnit::Celsius,
+    let config = Hcsr04Config {
+        temperature_c: TEMPERATURE,
+        ..Default::default()
     };
 
-    let clock = EmbassyClock;
-    let delay = Delay;
     interrupt::UART5.set_priority(Priority::P7);
     let mp_spawner = EXECUTOR_MED.start(interrupt::UART5);
-    let sonar = Hcsr04::new(trigger, echo, config, clock, delay);
+    let sonar = Hcsr04::new(trigger, echo, config);
 
     // Medium-priority executor: UART5, priority level 7
 
     let led = Output::new(p.PA5, Level::High, Speed::High);
 
-    mp_spawner.must_spawn(read_sonar(&SOME_SIGNAL, sonar));
+    mp_spawner.must_spawn(read_sonar(&SONAR_RANGE, sonar));
     spawner.must_spawn(blink(led));
 
-    spawner.must_spawn(follow_line(
-        &SOME_SIGNAL,
-        line_sensors,
-        left_motor,
-        right_motor,
-    ));
+    let drive = DifferentialDrive::new(left_motor, right_motor);
+
+    spawner.must_spawn(follow_line(&SONAR_RANGE, line_sensors, drive));
 }
 
 #[embassy_executor::task]
@@ -162,96 +162,88 @@ async fn blink(mut led: Output<'static>) {
 
 #[embassy_executor::task]
 async fn follow_line(
-    receiver: &'static MySignal,
+    sonar_range: &'static MySonarRange,
     mut sensors: MyLineSensor<'static>,
-    mut left_motor: LeftMotor<'static>,
-    mut right_motor: RightMotor<'static>,
+    mut drive: MyDrive<'static>,
 ) {
-    let mut integral = 0.0f32;
+    let mut pid = Pid::from_iteration_gains(KP, KI, KD, LINE_LOOP_PERIOD)
+        .with_output_limits(-2.0 * SPEED, 2.0 * SPEED)
+        .with_integral_limits(-KI * SPEED, KI * SPEED);
 
     let mut prev_deviation = 0.0f32;
-    let mut is_running = false;
 
     loop {
         Timer::after_nanos(500).await;
         let mut the_speed = SPEED;
 
-        if let Some(distance_cm) = receiver.try_take() {
-            // Possible cause of slugginess
-            if distance_cm >= MINIMUM_DISTANCE {
-                is_running = true;
-                //`` integral = 0.0;
-                // prev_deviation = 0.0;
-                debug!("{}", "Obstacle detected");
-                if distance_cm <= MINIMUM_DISTANCE * 1.8 {
-                    debug!("{}", "Comming to the obstacle");
-                    the_speed /= 1.15;
-                }
-            } else {
-                is_running = false;
-            }
+        let Some(distance) = sonar_range.clearance(SONAR_MAX_AGE) else {
+            // wait for the first measurement
+            drive.stop();
+            continue;
+        };
+        // Possible cause of slugginess
+        let obstacle_ahead = distance < MINIMUM_DISTANCE;
+        let mut is_running = !obstacle_ahead;
+        if obstacle_ahead {
+            debug!("{}", "Obstacle detected");
+        } else if distance <= MINIMUM_DISTANCE * 1.8 {
+            debug!("{}", "Comming to the obstacle");
+            the_speed /= 1.15;
         }
 
         let deviation = {
-            let mut sum = 0;
-            let mut activated = 0;
-            for (idx, sensor) in sensors.iter_mut().enumerate() {
-                if sensor.is_on_line() {
-                    activated += 1;
-                    sum += idx;
-                }
-            }
+            let reading = sensors.read();
 
-            if activated == 5 {
+            if reading.all_on {
                 is_running = false;
                 debug!("{}", "No line");
             }
 
-            if activated == 0 {
+            if reading.none_on {
                 prev_deviation
             } else {
-                MIDDLE - sum as f32 / activated as f32
+                // in sensor pitches, the gains were tuned for that scale
+                -reading.position_mm / SENSOR_SPACING_MM
             }
         };
         debug!("{}", deviation);
 
         if !is_running {
-            left_motor.stop();
-            right_motor.stop();
+            if obstacle_ahead {
+                drive.brake();
+            } else {
+                drive.stop();
+            }
             continue;
         }
 
-        integral = (integral + deviation).clamp(-the_speed, the_speed);
-
-        let diff = deviation - prev_deviation;
-
-        let pid_val = KP * deviation + KI * integral + KD * diff;
+        // the line position is the negated deviation, the setpoint is the middle sensor
+        let pid_val = pid.update_at(0.0, -deviation, Instant::now());
 
         let attenuation = 1.0 - KA * deviation.abs();
-        let left_speed = (the_speed - pid_val).clamp(-the_speed, the_speed) * attenuation;
-
-        let right_speed = (the_speed + pid_val).clamp(-the_speed, the_speed) * attenuation;
+        let left_speed = (the_speed - pid_val) * attenuation;
+        let right_speed = (the_speed + pid_val) * attenuation;
 
         prev_deviation = deviation;
 
-        left_motor.run(left_speed);
-
-        right_motor.run(right_speed);
+        drive.tank(left_speed, right_speed);
     }
 }
 
 #[embassy_executor::task]
-async fn read_sonar(sender: &'static MySignal, mut sonar: MySonar<'static>) {
+async fn read_sonar(sonar_range: &'static MySonarRange, mut sonar: MySonar<'static>) {
+    let mut filter = RangeFilter::<3>::new(Default::default());
     loop {
-        let measurment = sonar.measure(TEMPERATURE).await;
-
-        match measurment {
-            Ok(distance) => {
-                debug!("distance to obstacle: {}cm", distance);
-                sender.signal(distance);
-            }
-            Err(err) => defmt::error!("{:?}", err),
+        let measurement = sonar.try_measure().await;
+        match measurement {
+            Ok(range) => debug!("distance to obstacle: {}mm", range.distance_mm),
+            Err(RangeError::NoEcho) => debug!("no obstacle in range"),
+            Err(err) => defmt::error!("{}", err),
         };
+        sonar_range.record(&measurement);
+        if let Some(range) = filter.update(measurement) {
+            sonar_range.publish(range);
+        }
 
         Timer::after(SONAR_MEASURE_CYCLE).await; // for sensor to catch up with the polling rate
     }
@@ -11,6 +11,7 @@ use panic_probe as _;
 // Uncomment the BSP you included in Cargo.toml, the rest of the code does not need to change.
 use clumsy_stm_bot::{
     self as _,
+    drive::DifferentialDrive,
     drivers::{
         line_sensor::{LinePos, TrippleLineSensor},
         motor::Motor,
@@ -30,6 +31,7 @@ use clumsy_stm_bot as _;
 
 type LeftMotor<'a> = Motor<SimplePwmChannel<'a, TIM3>, Output<'a>, Output<'a>>;
 type RightMotor<'a> = Motor<SimplePwmChannel<'a, TIM2>, Output<'a>, Output<'a>>;
+type MyDrive<'a> = DifferentialDrive<LeftMotor<'a>, RightMotor<'a>>;
 type MyLineSensor<'a> = TrippleLineSensor<Input<'a>, Input<'a>, Input<'a>>;
 
 const SPEED: f32 = 100.0;
@@ -89,7 +91,9 @@ async fn main(spawner: Spawner) {
         Input::new(p.PB3, Pull::Down),
     );
 
-    spawner.must_spawn(follow_line(line_sensor, left_motor, right_motor));
+    let drive = DifferentialDrive::new(left_motor, right_motor);
+
+    spawner.must_spawn(follow_line(line_sensor, drive));
 }
 
 #[embassy_executor::task]
@@ -101,39 +105,29 @@ async fn blink(mut led: Output<'static>) {
 }
 
 #[embassy_executor::task]
-async fn follow_line(
-    mut sensor: MyLineSensor<'static>,
-    mut left_motor: LeftMotor<'static>,
-    mut right_motor: RightMotor<'static>,
-) {
+async fn follow_line(mut sensor: MyLineSensor<'static>, mut drive: MyDrive<'static>) {
     loop {
         Timer::after_nanos(50).await;
 
         match sensor.read() {
             LinePos::NoLine => {
-                left_motor.stop();
-                right_motor.stop();
+                drive.stop();
                 continue;
             }
             LinePos::Lefter => {
-                left_motor.run(-SPEED);
-                right_motor.run(SPEED);
+                drive.tank(-SPEED, SPEED);
             }
             LinePos::Left => {
-                left_motor.run(SPEED / 4.0);
-                right_motor.run(SPEED * 10.0 / 12.0);
+                drive.tank(SPEED / 4.0, SPEED * 10.0 / 12.0);
             }
             LinePos::Middle => {
-                left_motor.run(SPEED);
-                right_motor.run(SPEED);
+                drive.tank(SPEED, SPEED);
             }
             LinePos::Right => {
-                left_motor.run(SPEED * 10.0 / 12.0);
-                right_motor.run(SPEED / 4.0);
+                drive.tank(SPEED * 10.0 / 12.0, SPEED / 4.0);
             }
             LinePos::Righter => {
-                left_motor.run(SPEED * 10.0 / 12.0);
-                right_motor.run(-SPEED * 10.0 / 12.0);
+                drive.tank(SPEED * 10.0 / 12.0, -SPEED * 10.0 / 12.0);
             }
         };
     }
@@ -11,8 +11,10 @@ use panic_probe as _;
 // Uncomment the BSP you included in Cargo.toml, the rest of the code does not need to change.
 use clumsy_stm_bot::{
     self as _,
+    control::Pid,
+    drive::DifferentialDrive,
     drivers::{
-        line_sensor::{LinePos, TrippleLineSensor},
+        line_sensor::{LINE_LOOP_PERIOD, LinePos, TrippleLineSensor},
         motor::Motor,
     },
 };
@@ -24,12 +26,13 @@ use embassy_stm32::{
     time::khz,
     timer::simple_pwm::{PwmPin, SimplePwm, SimplePwmChannel},
 };
-use embassy_time::Timer;
+use embassy_time::{Instant, Timer};
 
 use clumsy_stm_bot as _;
 
 type LeftMotor<'a> = Motor<SimplePwmChannel<'a, TIM3>, Output<'a>, Output<'a>>;
 type RightMotor<'a> = Motor<SimplePwmChannel<'a, TIM2>, Output<'a>, Output<'a>>;
+type MyDrive<'a> = DifferentialDrive<LeftMotor<'a>, RightMotor<'a>>;
 type MyLineSensor<'a> = TrippleLineSensor<Input<'a>, Input<'a>, Input<'a>>;
 
 const SPEED: f32 = 100.0;
@@ -97,7 +100,9 @@ async fn main(spawner: Spawner) {
         Input::new(p.PB3, Pull::Down),
     );
 
-    spawner.must_spawn(follow_line(line_sensor, left_motor, right_motor));
+    let drive = DifferentialDrive::new(left_motor, right_motor);
+
+    spawner.must_spawn(follow_line(line_sensor, drive));
 }
 
 #[embassy_executor::task]
@@ -109,21 +114,17 @@ async fn blink(mut led: Output<'static>) {
 }
 
 #[embassy_executor::task]
-async fn follow_line(
-    mut sensor: MyLineSensor<'static>,
-    mut left_motor: LeftMotor<'static>,
-    mut right_motor: RightMotor<'static>,
-) {
-    let mut integral = 0.0f32;
-
-    let mut prev_deviation = 0.0f32;
+async fn follow_line(mut sensor: MyLineSensor<'static>, mut drive: MyDrive<'static>) {
+    let mut pid = Pid::from_iteration_gains(KP, KI, KD, LINE_LOOP_PERIOD)
+        .with_output_limits(-2.0 * SPEED, 2.0 * SPEED)
+        .with_integral_limits(-KI * SPEED, KI * SPEED);
+
     loop {
         Timer::after_nanos(50).await;
         let line_pos = sensor.read();
         let deviation = match line_pos {
             LinePos::NoLine => {
-                left_motor.stop();
-                right_motor.stop();
+                drive.stop();
                 continue;
             }
             LinePos::Lefter => -2.0,
@@ -133,21 +134,12 @@ async fn follow_line(
             LinePos::Righter => 2.0,
         };
 
-        integral = (integral + deviation).clamp(-SPEED, SPEED);
-
-        let diff = deviation - prev_deviation;
-
-        let pid_val = KP * deviation + KI * integral + KD * diff;
+        let pid_val = pid.update_at(0.0, deviation, Instant::now());
 
         let attenuation = 1.0 - KA * deviation.abs();
-        let left_speed = (SPEED + pid_val).clamp(-SPEED, SPEED) * attenuation;
-
-        let right_speed = (SPEED - pid_val).clamp(-SPEED, SPEED) * attenuation;
-
-        prev_deviation = deviation;
-
-        left_motor.run(left_speed);
+        let left_speed = (SPEED - pid_val) * attenuation;
+        let right_speed = (SPEED + pid_val) * attenuation;
 
-        right_motor.run(right_speed);
+        drive.tank(left_speed, right_speed);
     }
 }
@@ -4,13 +4,21 @@
 #![no_std]
 #![no_main]
 
-use defmt::debug;
+use defmt::{debug, warn};
 use defmt_rtt as _;
 use embassy_stm32 as _;
 use panic_probe as _;
 // Provide an alias for our BSP so we can switch targets quickly.
 // Uncomment the BSP you included in Cargo.toml, the rest of the code does not need to change.
-use clumsy_stm_bot::drivers::{line_sensor::LineSensor, motor::Motor};
+use clumsy_stm_bot::{
+    calibration::{SpinCalibrationConfig, calibrate_spin},
+    control::Pid,
+    drive::DifferentialDrive,
+    drivers::{
+        line_sensor::{LINE_LOOP_PERIOD, LineArray, LineSensor},
+        motor::Motor,
+    },
+};
 
 use embassy_executor::Spawner;
 use embassy_stm32::{
@@ -19,13 +27,14 @@ use embassy_stm32::{
     time::khz,
     timer::simple_pwm::{PwmPin, SimplePwm, SimplePwmChannel},
 };
-use embassy_time::Timer;
+use embassy_time::{Delay, Instant, Timer};
 
 use clumsy_stm_bot as _;
 
 type LeftMotor<'a> = Motor<SimplePwmChannel<'a, TIM3>, Output<'a>, Output<'a>>;
 type RightMotor<'a> = Motor<SimplePwmChannel<'a, TIM2>, Output<'a>, Output<'a>>;
-type MyLineSensor<'a> = [LineSensor<Input<'a>>; 5];
+type MyDrive<'a> = DifferentialDrive<LeftMotor<'a>, RightMotor<'a>>;
+type MyLineSensor<'a> = LineArray<Input<'a>, 5>;
 
 const SPEED: f32 = 100.0;
 
@@ -37,7 +46,7 @@ const KD: f32 = 100.0;
 
 const KA: f32 = 0.000; // reduction of the movement speed
 
-const MIDDLE: f32 = 2.0;
+const SENSOR_SPACING_MM: f32 = 10.0;
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
@@ -85,14 +94,42 @@ async fn main(spawner: Spawner) {
         0.0,
         Default::default(),
     );
-    let line_sensors = [
-        LineSensor::new_invert(Input::new(p.PB0, Pull::Down)),
-        LineSensor::new(Input::new(p.PB4, Pull::Down)),
-        LineSensor::new(Input::new(p.PB5, Pull::Down)),
-        LineSensor::new(Input::new(p.PB3, Pull::Down)),
-        LineSensor::new_invert(Input::new(p.PA4, Pull::Down)),
+    let mut drive = DifferentialDrive::new(left_motor, right_motor);
+
+    let mut line_pins = [
+        Input::new(p.PB0, Pull::Down),
+        Input::new(p.PB4, Pull::Down),
+        Input::new(p.PB5, Pull::Down),
+        Input::new(p.PB3, Pull::Down),
+        Input::new(p.PA4, Pull::Down),
     ];
-    spawner.must_spawn(follow_line(line_sensors, left_motor, right_motor));
+
+    // start with the sensors over the line
+    let calibration = calibrate_spin(
+        &mut line_pins,
+        &mut drive,
+        &mut Delay,
+        SpinCalibrationConfig::default(),
+    )
+    .await;
+
+    let sensors = match calibration {
+        Ok(calibration) if calibration.is_valid() => calibration.line_sensors(line_pins),
+        _ => {
+            warn!("line calibration failed, using the default polarity");
+            let [a, b, c, d, e] = line_pins;
+            [
+                LineSensor::new_invert(a),
+                LineSensor::new(b),
+                LineSensor::new(c),
+                LineSensor::new(d),
+                LineSensor::new_invert(e),
+            ]
+        }
+    };
+    let line_sensors = LineArray::new(sensors, SENSOR_SPACING_MM);
+
+    spawner.must_spawn(follow_line(line_sensors, drive));
 
     let led = Output::new(p.PA5, Level::High, Speed::High);
     spawner.spawn(blink(led)).unwrap();
@@ -107,37 +144,28 @@ async fn blink(mut led: Output<'static>) {
 }
 
 #[embassy_executor::task]
-async fn follow_line(
-    mut sensors: MyLineSensor<'static>,
-    mut left_motor: LeftMotor<'static>,
-    mut right_motor: RightMotor<'static>,
-) {
-    let mut integral = 0.0f32;
+async fn follow_line(mut sensors: MyLineSensor<'static>, mut drive: MyDrive<'static>) {
+    let mut pid = Pid::from_iteration_gains(KP, KI, KD, LINE_LOOP_PERIOD)
+        .with_output_limits(-2.0 * SPEED, 2.0 * SPEED)
+        .with_integral_limits(-KI * SPEED, KI * SPEED);
 
     let mut prev_deviation = 0.0f32;
     loop {
         Timer::after_nanos(50).await;
         let deviation = {
-            let mut sum = 0;
-            let mut activated = 0;
-            for (idx, sensor) in sensors.iter_mut().enumerate() {
-                if sensor.is_on_line() {
-                    activated += 1;
-                    sum += idx;
-                }
-            }
+            let reading = sensors.read();
 
-            if activated == 5 {
-                left_motor.stop();
-                right_motor.stop();
+            if reading.all_on {
+                drive.stop();
                 debug!("{}", "No line");
                 continue;
             }
 
-            if activated == 0 {
+            if reading.none_on {
                 prev_deviation
             } else {
-                MIDDLE - sum as f32 / activated as f32
+                // in sensor pitches, the gains were tuned for that scale
+                -reading.position_mm / SENSOR_SPACING_MM
             }
         };
 
@@ -156,21 +184,15 @@ async fn follow_line(
         //     LinePos::Righter => 2.0,
         // };
 
-        integral = (integral + deviation).clamp(-SPEED, SPEED);
-
-        let diff = deviation - prev_deviation;
-
-        let pid_val = KP * deviation + KI * integral + KD * diff;
+        // the line position is the negated deviation, the setpoint is the middle sensor
+        let pid_val = pid.update_at(0.0, -deviation, Instant::now());
 
         let attenuation = 1.0 - KA * deviation.abs();
-        let left_speed = (SPEED - pid_val).clamp(-SPEED, SPEED) * attenuation;
-
-        let right_speed = (SPEED + pid_val).clamp(-SPEED, SPEED) * attenuation;
+        let left_speed = (SPEED - pid_val) * attenuation;
+        let right_speed = (SPEED + pid_val) * attenuation;
 
         prev_deviation = deviation;
 
-        left_motor.run(left_speed);
-
-        right_motor.run(right_speed);
+        drive.tank(left_speed, right_speed);
     }
 }
@@ -2,7 +2,7 @@
 #![no_main]
 
 use clumsy_stm_bot as _;
-use clumsy_stm_bot::drivers::servo::Servo;
+use clumsy_stm_bot::drivers::servo::{Servo, ServoConfig};
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_stm32::gpio::OutputType;
@@ -34,7 +34,7 @@ async fn main(_spawner: Spawner) {
     let max_duty = ch3.max_duty_cycle();
     info!("PWM max duty {}", max_duty);
 
-    let mut servo = Servo::new(ch3, 20u8, 180.0, max_duty);
+    let mut servo = Servo::new(ch3, ServoConfig::default());
 
     loop {
         for angle in 0..180 {
@@ -1,12 +1,15 @@
 #![no_std]
 #![no_main]
 
+use clumsy_stm_bot::drivers::{
+    hcsr04::{Hcsr04, Hcsr04Config},
+    range::RangeSensor,
+};
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_stm32::gpio::{Output, Pull, Speed};
 use embassy_stm32::{exti::ExtiInput, gpio::Level};
-use embassy_time::{Delay, Duration, Instant, Timer};
-use hcsr04_async::{DistanceUnit, Hcsr04, TemperatureUnit};
+use embassy_time::{Duration, Timer};
 use {defmt_rtt as _, panic_probe as _};
 
 #[embassy_executor::main]
@@ -17,33 +20,20 @@ async fn main(_spawner: Spawner) {
     let trigger = Output::new(p.PC0, Level::Low, Speed::High);
     let echo = ExtiInput::new(p.PC1, p.EXTI1, Pull::Down);
 
-    struct EmbassyClock;
-
-    impl hcsr04_async::Now for EmbassyClock {
-        fn now_micros(&self) -> u64 {
-            Instant::now().as_micros()
-        }
-    }
-
-    let clock = EmbassyClock;
-    let delay = Delay;
-
-    let config = hcsr04_async::Config {
-        distance_unit: DistanceUnit::Centimeters,
-        temperature_unit: TemperatureUnit::Celsius,
-    };
-
-    let mut sensor = Hcsr04::new(trigger, echo, config, clock, delay);
-
     // The temperature of the environment, if known, can be used to adjust the speed of sound.
     // If unknown, an average estimate must be used.
-    let temperature = 24.0;
+    let config = Hcsr04Config {
+        temperature_c: 24.0,
+        ..Default::default()
+    };
+
+    let mut sensor = Hcsr04::new(trigger, echo, config);
 
     loop {
-        let distance = sensor.measure(temperature).await;
+        let distance = sensor.try_measure().await;
         match distance {
-            Ok(distance) => {
-                info!("Distance: {} cm", distance);
+            Ok(range) => {
+                info!("Distance: {} mm", range.distance_mm);
             }
             Err(e) => {
                 info!("Error: {:?}", e);
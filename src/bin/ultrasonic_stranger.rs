@@ -3,11 +3,18 @@
 
 use clumsy_stm_bot::{
     self as _,
+    control::HeadingHold,
+    drive::DifferentialDrive,
     drivers::{
+        hcsr04::{Hcsr04, Hcsr04Config},
+        imu::{Mpu6050, SharedHeading},
         line_sensor::{LinePos, TrippleLineSensor},
-        motor::Motor,
-        servo::Servo,
+        motor::{DcMotor, Motor},
+        range::{Range, RangeError, RangeSensor, clearance_mm},
+        servo::{Servo, ServoConfig},
     },
+    error::Error,
+    nav::{Motion, MotionConfig, Progress, TimedModel, WithGyro},
 };
 
 use defmt_rtt as _;
@@ -16,56 +23,67 @@ use embassy_stm32::{
     self as _,
     interrupt::{InterruptExt, Priority},
 };
-use embassy_stm32::{interrupt, peripherals::TIM1};
+use embassy_stm32::{bind_interrupts, i2c, interrupt, peripherals, peripherals::TIM1};
 use panic_probe as _;
 
 use defmt::debug;
 use embassy_stm32::{
     exti::ExtiInput,
     gpio::{Input, Level, Output, OutputType, Pull, Speed},
+    i2c::I2c,
+    mode::Async,
     peripherals::{TIM2, TIM3},
     time::hz,
     timer::simple_pwm::{PwmPin, SimplePwm, SimplePwmChannel},
 };
 use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
 use embassy_sync::{
     blocking_mutex::raw::CriticalSectionRawMutex,
     channel::{Receiver, Sender},
 };
-use embassy_time::{Delay, Duration, Instant, Timer};
-use hcsr04_async::{DistanceUnit, Hcsr04, TemperatureUnit};
+use embassy_time::{Delay, Duration, Instant, Ticker, Timer};
 
-type LeftMotor<'a> = Motor<SimplePwmChannel<'a, TIM3>, Output<'a>, Output<'a>>;
-type RightMotor<'a> = Motor<SimplePwmChannel<'a, TIM2>, Output<'a>, Output<'a>>;
+use core::convert::Infallible;
 
-struct EmbassyClock;
+bind_interrupts!(struct Irqs {
+    I2C1_EV => i2c::EventInterruptHandler<peripherals::I2C1>;
+    I2C1_ER => i2c::ErrorInterruptHandler<peripherals::I2C1>;
+});
 
-impl hcsr04_async::Now for EmbassyClock {
-    fn now_micros(&self) -> u64 {
-        Instant::now().as_micros()
-    }
-}
+type LeftMotor<'a> = Motor<SimplePwmChannel<'a, TIM3>, Output<'a>, Output<'a>>;
+type RightMotor<'a> = Motor<SimplePwmChannel<'a, TIM2>, Output<'a>, Output<'a>>;
+type MyDrive<'a> = DifferentialDrive<LeftMotor<'a>, RightMotor<'a>>;
 
-type MySonar<'a> = Hcsr04<Output<'a>, ExtiInput<'a>, EmbassyClock, Delay>;
+type MySonar<'a> = Hcsr04<Output<'a>, ExtiInput<'a>>;
 type MyLineSensor<'a> = TrippleLineSensor<Input<'a>, Input<'a>, Input<'a>>;
 
-type Distance = f64;
+type Measurement = Result<Range, RangeError>;
 type MyMutex = CriticalSectionRawMutex;
-type MyReceiver<'a> = Receiver<'a, MyMutex, Distance, 1>;
-type MySender<'a> = Sender<'a, MyMutex, Distance, 1>;
+type MyReceiver<'a> = Receiver<'a, MyMutex, Measurement, 1>;
+type MySender<'a> = Sender<'a, MyMutex, Measurement, 1>;
 type MyServo<'a> = Servo<SimplePwmChannel<'a, TIM1>>;
+type MyImu<'a> = Mpu6050<I2c<'a, Async, i2c::Master>>;
 
 // The temperature of the environment, if known, can be used to adjust the speed of sound.
 // If unknown, an average estimate must be used.
-const TEMPERATURE: f64 = 25.0;
+const TEMPERATURE: f32 = 25.0;
 
 const SPEED: f32 = 100.0;
 
-const MINIMUM_DISTANCE: f64 = 18.0; // cm
+const MINIMUM_DISTANCE: f32 = 180.0; // mm
+
+const SERVO_SPEED: f32 = 360.0; // deg/s, sweeping the sonar
 
 const SONAR_MEASURE_CYCLE: Duration = Duration::from_millis(60);
 
-static CHANNEL: Channel<MyMutex, Distance, 1> = Channel::new();
+const IMU_PERIOD: Duration = Duration::from_millis(10);
+
+static CHANNEL: Channel<MyMutex, Measurement, 1> = Channel::new();
+
+static HEADING: SharedHeading = SharedHeading::new();
+// whether HEADING follows the gyro, sent once the IMU is set up or has failed
+static GYRO_READY: Signal<MyMutex, bool> = Signal::new();
 
 static EXECUTOR_MED: InterruptExecutor = InterruptExecutor::new();
 
@@ -127,15 +145,11 @@ async fn main(spawner: Spawner) {
     let trigger = Output::new(p.PC0, Level::Low, Speed::High);
     let echo = ExtiInput::new(p.PC1, p.EXTI1, Pull::None);
 
-    let config = hcsr04_async::Config {
-        distance_unit: DistanceUnit::Centimeters,
-        temperature_unit: TemperatureUnit::Celsius,
+    let config = Hcsr04Config {
+        temperature_c: TEMPERATURE,
+        ..Default::default()
     };
-
-    let clock = EmbassyClock;
-    let delay = Delay;
-
-    let sonar = Hcsr04::new(trigger, echo, config, clock, delay);
+    let sonar = Hcsr04::new(trigger, echo, config);
 
     // Medium-priority executor: UART5, priority level 7
     interrupt::UART5.set_priority(Priority::P7);
@@ -157,89 +171,165 @@ async fn main(spawner: Spawner) {
     let mut ch3 = pwm.split().ch3;
     ch3.enable();
 
-    let max_duty = ch3.max_duty_cycle();
-
-    let servo = Servo::new(ch3, 20u8, 180.0, max_duty);
+    // straight ahead is 0°, positive looks to the left
+    let servo_config = ServoConfig {
+        min_angle: -90.0,
+        max_angle: 90.0,
+        ..Default::default()
+    };
+    let servo = Servo::new(ch3, servo_config);
+
+    // MPU-6050 on the Arduino header, D15 SCL and D14 SDA
+    let i2c = I2c::new(
+        p.I2C1,
+        p.PB8,
+        p.PB9,
+        Irqs,
+        p.DMA1_CH6,
+        p.DMA1_CH7,
+        Default::default(),
+    );
+    let imu = Mpu6050::new(i2c);
 
     spawner.spawn(blink(led)).unwrap();
+    spawner.must_spawn(track_heading(imu));
     mp_spawner.must_spawn(read_sonar(sender, sonar));
 
-    spawner.must_spawn(roam(receiver, line_sensor, servo, left_motor, right_motor));
+    let drive = DifferentialDrive::new(left_motor, right_motor);
+
+    spawner.must_spawn(roam(receiver, line_sensor, servo, drive));
 }
 
 #[embassy_executor::task]
 async fn read_sonar(sender: MySender<'static>, mut sonar: MySonar<'static>) {
     loop {
-        let measurment = sonar.measure(TEMPERATURE).await;
-
-        match measurment {
-            Ok(distance) => {
-                debug!("distance to obstacle: {}cm", distance);
-                sender.send(distance).await;
-            }
-            Err(err) => defmt::error!("{:?}", err),
+        let measurement = sonar.try_measure().await;
+        match measurement {
+            Ok(range) => debug!("distance to obstacle: {}mm", range.distance_mm),
+            Err(RangeError::NoEcho) => debug!("no obstacle in range"),
+            Err(err) => defmt::error!("{}", err),
         };
+        sender.send(measurement).await;
 
         Timer::after(SONAR_MEASURE_CYCLE).await; // for sensor to catch up with the polling rate
     }
 }
 
+#[embassy_executor::task]
+async fn track_heading(mut imu: MyImu<'static>) {
+    let mut delay = Delay;
+    if let Err(err) = imu.try_init(&mut delay).await {
+        defmt::error!("imu init failed: {}", err);
+        GYRO_READY.signal(false);
+        return;
+    }
+    // the robot stands still right after power up
+    if let Err(err) = imu
+        .try_calibrate_gyro(100, Duration::from_millis(5), &mut delay)
+        .await
+    {
+        defmt::error!("gyro calibration failed: {}", err);
+    }
+    GYRO_READY.signal(true);
+
+    let mut ticker = Ticker::every(IMU_PERIOD);
+    loop {
+        ticker.next().await;
+        match imu.try_update_yaw_at(Instant::now()).await {
+            Ok(yaw) => HEADING.set(yaw),
+            Err(err) => defmt::error!("imu: {}", err),
+        }
+    }
+}
+
 #[embassy_executor::task]
 async fn roam(
+    receiver: MyReceiver<'static>,
+    line_sensor: MyLineSensor<'static>,
+    servo: MyServo<'static>,
+    drive: MyDrive<'static>,
+) {
+    let model = TimedModel::new(drive.geometry());
+    // a timed out turn or a failing heading source ends the roaming
+    let Err(err) = if GYRO_READY.wait().await {
+        // no encoders: the gyro measures the turns, distances are timed from the drive geometry,
+        // and it keeps the straight runs straight as the motors are not matched
+        let progress = WithGyro::new(model, &HEADING);
+        let hold = Some(HeadingHold::new(&HEADING));
+        wander(receiver, line_sensor, servo, drive, progress, hold).await
+    } else {
+        // without the gyro the turns are timed as well
+        wander(receiver, line_sensor, servo, drive, model, None).await
+    };
+    defmt::error!("roaming stopped: {}", err);
+}
+
+async fn wander(
     receiver: MyReceiver<'static>,
     mut line_sensor: MyLineSensor<'static>,
     mut servo: MyServo<'static>,
-    mut left: LeftMotor<'static>,
-    mut right: RightMotor<'static>,
-) {
+    mut drive: DifferentialDrive<impl DcMotor, impl DcMotor>,
+    progress: impl Progress,
+    mut hold: Option<HeadingHold<&'static SharedHeading>>,
+) -> Result<Infallible, Error> {
     let speed = SPEED;
+    let mut motion = Motion::new(progress, MotionConfig::default());
 
     // center the sonar
-    servo.set_angle(90.0);
+    servo.center();
     loop {
         if line_sensor.read() != LinePos::NoLine {
             // Stumbled on Line
-            left.stop();
-            right.stop();
+            if let Some(hold) = &mut hold {
+                hold.disengage();
+            }
+            drive.try_stop()?;
         }
 
-        let distance_cm = receiver.receive().await;
-        if distance_cm >= MINIMUM_DISTANCE {
-            left.run(speed);
-            right.run(speed);
+        let distance = clearance_mm(&receiver.receive().await);
+        if distance >= MINIMUM_DISTANCE {
+            match &mut hold {
+                Some(hold) => hold.try_tank_at(&mut drive, speed, speed, Instant::now())?,
+                None => drive.try_tank(speed, speed)?,
+            }
         } else {
-            left.stop();
-            right.stop();
-
-            servo.set_angle(180.0);
-            Timer::after_millis(300).await;
-            let distance_cm_left = receiver.receive().await;
-            servo.set_angle(0.0);
-            Timer::after_millis(300).await;
-            let distance_cm_right = receiver.receive().await;
-            servo.set_angle(90.0);
-            Timer::after_millis(300).await;
-
-            if distance_cm_left <= MINIMUM_DISTANCE && distance_cm_right <= MINIMUM_DISTANCE {
+            // brake hard so the robot does not roll into the obstacle
+            if let Some(hold) = &mut hold {
+                hold.disengage();
+            }
+            drive.try_brake()?;
+
+            servo.move_to(90.0, SERVO_SPEED).await;
+            let distance_left = clearance_mm(&measure_settled(&receiver).await);
+            servo.move_to(-90.0, SERVO_SPEED).await;
+            let distance_right = clearance_mm(&measure_settled(&receiver).await);
+            servo.move_to(0.0, SERVO_SPEED).await;
+
+            if distance_left <= MINIMUM_DISTANCE && distance_right <= MINIMUM_DISTANCE {
                 // turn back
-                left.run(-speed);
-                right.run(-speed);
-            } else if distance_cm_left < distance_cm_right {
-                // turn right
-                left.run(speed);
-                right.run(-speed);
+                motion.try_turn_by(&mut drive, 180.0).await?;
+            } else if distance_left < distance_right {
+                // turn right in place
+                motion.try_turn_by(&mut drive, -90.0).await?;
             } else {
-                // turn left
-                left.run(-speed);
-                right.run(speed);
+                // turn left in place
+                motion.try_turn_by(&mut drive, 90.0).await?;
             }
-            Timer::after_millis(500).await;
         }
 
         Timer::after_nanos(50).await;
     }
 }
 
+/// The first measurement started after the call, e.g. once the servo has settled.
+async fn measure_settled(receiver: &MyReceiver<'static>) -> Measurement {
+    receiver.clear();
+    // a sender blocked on the full channel still hands over its older reading,
+    // and one in the middle of measuring started before the call
+    let _ = receiver.receive().await;
+    receiver.receive().await
+}
+
 #[embassy_executor::task]
 async fn blink(mut led: Output<'static>) {
     loop {
@@ -0,0 +1,242 @@
+use defmt::debug;
+use embassy_time::Duration;
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal::pwm::SetDutyCycle;
+use embedded_hal_async::delay::DelayNs;
+
+use crate::drive::DifferentialDrive;
+use crate::drivers::adc::AnalogInputs;
+use crate::drivers::line_sensor::{ChannelCalibration, LineSensor};
+use crate::drivers::motor::{DcMotor, Motor, MotorCalibration};
+use crate::error::Error;
+
+/// What one sensor saw while sweeping over the field and the line.
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub struct SensorCalibration {
+    pub min: u16,
+    pub max: u16,
+    /// Raw readings at or above this are "high".
+    pub threshold: u16,
+    /// The line gives the high readings. The field covers most of a sweep, so
+    /// the line is whichever level is seen less often. For the analog sensors,
+    /// which read higher over darker surfaces, this means a dark line.
+    pub line_reads_high: bool,
+}
+
+impl SensorCalibration {
+    /// False when the sensor never saw any contrast during the sweep.
+    pub fn is_valid(&self) -> bool {
+        self.max > self.min
+    }
+
+    pub fn is_on_line(&self, raw: u16) -> bool {
+        (raw >= self.threshold) == self.line_reads_high
+    }
+}
+
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct LineCalibration<const N: usize> {
+    pub sensors: [SensorCalibration; N],
+}
+
+impl<const N: usize> LineCalibration<N> {
+    pub fn is_valid(&self) -> bool {
+        self.sensors.iter().all(SensorCalibration::is_valid)
+    }
+
+    /// Digital line sensors with the polarity found during calibration.
+    pub fn line_sensors<P: InputPin>(&self, pins: [P; N]) -> [LineSensor<P>; N] {
+        let mut polarity = self.sensors.iter().map(|s| s.line_reads_high);
+        pins.map(|pin| match polarity.next() {
+            Some(false) => LineSensor::new_invert(pin),
+            _ => LineSensor::new(pin),
+        })
+    }
+
+    /// Min/max ranges for an `AnalogLineArray`.
+    pub fn channel_calibration(&self) -> [ChannelCalibration; N] {
+        self.sensors.map(|s| ChannelCalibration {
+            min: s.min,
+            max: s.max,
+        })
+    }
+}
+
+/// Accumulates raw samples of `N` sensors into a `LineCalibration`.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct CalibrationSampler<const N: usize> {
+    ranges: [ChannelCalibration; N],
+    sums: [u32; N],
+    samples: u32,
+}
+
+impl<const N: usize> Default for CalibrationSampler<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> CalibrationSampler<N> {
+    pub fn new() -> Self {
+        Self {
+            ranges: [ChannelCalibration::default(); N],
+            sums: [0; N],
+            samples: 0,
+        }
+    }
+
+    pub fn add(&mut self, raw: &[u16; N]) {
+        for ((range, sum), value) in self.ranges.iter_mut().zip(&mut self.sums).zip(raw) {
+            range.update(*value);
+            *sum += *value as u32;
+        }
+        self.samples += 1;
+    }
+
+    pub fn finish(&self) -> LineCalibration<N> {
+        let mut sensors = [SensorCalibration::default(); N];
+        if self.samples == 0 {
+            return LineCalibration { sensors };
+        }
+
+        for ((sensor, range), sum) in sensors.iter_mut().zip(&self.ranges).zip(&self.sums) {
+            let span = range.min as u64 + range.max as u64;
+            // the field dominates, so a mean below the midpoint means the line is high
+            let mean_below_mid = 2 * *sum as u64 <= span * self.samples as u64;
+            *sensor = SensorCalibration {
+                min: range.min,
+                max: range.max,
+                threshold: span.div_ceil(2) as u16,
+                line_reads_high: mean_below_mid,
+            };
+        }
+        LineCalibration { sensors }
+    }
+}
+
+/// Timing of the spin-in-place calibration sweep.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct SpinCalibrationConfig {
+    /// Motor speed in percent, positive turns clockwise first.
+    pub speed: f32,
+    /// Whole sweep, half of it is spent turning each way.
+    pub duration: Duration,
+    pub sample_interval: Duration,
+}
+
+impl Default for SpinCalibrationConfig {
+    fn default() -> Self {
+        Self {
+            speed: 40.0,
+            duration: Duration::from_secs(2),
+            sample_interval: Duration::from_millis(5),
+        }
+    }
+}
+
+/// Spins the robot in place, first one way then back, sampling every sensor,
+/// and derives a threshold and polarity for each of them.
+///
+/// The robot must start with the sensors over the line so the sweep crosses it.
+/// Both motors are stopped when the sweep ends, also on a sensor or motor error.
+pub async fn calibrate_spin<A, L, R, D, const N: usize>(
+    inputs: &mut A,
+    drive: &mut DifferentialDrive<L, R>,
+    delay: &mut D,
+    config: SpinCalibrationConfig,
+) -> Result<LineCalibration<N>, Error>
+where
+    A: AnalogInputs<N>,
+    L: DcMotor,
+    R: DcMotor,
+    D: DelayNs,
+{
+    let interval_us = config.sample_interval.as_micros().max(1);
+    let half_sweep = (config.duration.as_micros() / interval_us / 2).max(1);
+    let mut sampler = CalibrationSampler::new();
+
+    let result = async {
+        for speed in [config.speed, -config.speed] {
+            drive.try_tank(speed, -speed)?;
+            for _ in 0..half_sweep {
+                sampler.add(&inputs.try_read().map_err(Into::into)?);
+                delay.delay_us(interval_us as u32).await;
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    let stopped = drive.try_stop();
+    result.and(stopped)?;
+
+    let calibration = sampler.finish();
+    debug!("line calibration {}", calibration);
+    Ok(calibration)
+}
+
+/// Steps of the motor start threshold search.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct StartDutyConfig {
+    /// Duty increase in percent between two attempts.
+    pub step: f32,
+    /// Time given to the motor to start after every step.
+    pub settle: Duration,
+}
+
+impl Default for StartDutyConfig {
+    fn default() -> Self {
+        Self {
+            step: 1.0,
+            settle: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Raises the duty of `motor` step by step until `is_moving` reports motion,
+/// e.g. from an encoder, and returns the motor's calibration with that duty
+/// as `min_duty`. `None` means the motor did not start even at full duty.
+///
+/// The motor's own calibration is bypassed during the search and left unchanged.
+pub async fn find_start_duty<T, U, W, D, F>(
+    motor: &mut Motor<T, U, W>,
+    mut is_moving: F,
+    delay: &mut D,
+    config: StartDutyConfig,
+) -> Result<Option<MotorCalibration>, Error>
+where
+    T: SetDutyCycle,
+    U: OutputPin,
+    W: OutputPin,
+    D: DelayNs,
+    F: FnMut() -> bool,
+{
+    let calibration = motor.calibration();
+    motor.set_calibration(MotorCalibration::default());
+
+    let settle_us = config.settle.as_micros() as u32;
+    let step = config.step.max(0.1);
+    let mut duty = 0.0;
+    let result = async {
+        while duty < 100.0 {
+            duty = (duty + step).min(100.0);
+            motor.try_run(duty)?;
+            delay.delay_us(settle_us).await;
+            if is_moving() {
+                return Ok(Some(duty));
+            }
+        }
+        Ok(None)
+    }
+    .await;
+
+    motor.set_calibration(calibration);
+    let stopped = motor.try_stop();
+    let start_duty = result.and_then(|duty| stopped.map(|_| duty))?;
+
+    debug!("motor start duty {}", start_duty);
+    Ok(start_duty.map(|min_duty| MotorCalibration {
+        min_duty,
+        ..calibration
+    }))
+}
@@ -0,0 +1,9 @@
+pub mod heading;
+pub mod pid;
+pub mod slew;
+pub mod velocity;
+
+pub use heading::HeadingHold;
+pub use pid::{AntiWindup, Pid};
+pub use slew::SlewLimiter;
+pub use velocity::{Feedforward, TargetSpeed, VelocityMotor};
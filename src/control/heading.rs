@@ -0,0 +1,90 @@
+use embassy_time::Instant;
+
+use crate::control::Pid;
+use crate::drive::DifferentialDrive;
+use crate::drivers::motor::DcMotor;
+use crate::error::Error;
+use crate::nav::motion::HeadingSource;
+
+/// Keeps the robot on the heading it had when a straight run started, by
+/// trimming the duty of both wheels in opposite directions.
+///
+/// A run is straight while both wheels get the same non-zero duty. Any other
+/// command, a turn or a stop, disengages the hold and the next straight run
+/// starts from the heading at that moment.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct HeadingHold<H: HeadingSource> {
+    source: H,
+    pid: Pid,
+    target: Option<f32>,
+}
+
+impl<H: HeadingSource> HeadingHold<H> {
+    pub fn new(source: H) -> Self {
+        Self {
+            source,
+            // percent of duty per radian off, at most a fifth of full duty
+            pid: Pid::new(60.0, 20.0, 0.0).with_output_limits(-20.0, 20.0),
+            target: None,
+        }
+    }
+
+    /// Gains in percent of duty per radian, the output is the trim added to the right wheel.
+    pub fn with_pid(mut self, pid: Pid) -> Self {
+        self.pid = pid;
+        self
+    }
+
+    pub fn source_mut(&mut self) -> &mut H {
+        &mut self.source
+    }
+
+    /// Heading being held, `None` while disengaged.
+    pub fn target(&self) -> Option<f32> {
+        self.target
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.target.is_some()
+    }
+
+    /// Forgets the held heading, e.g. before a turn driven by something else.
+    pub fn disengage(&mut self) {
+        self.target = None;
+        self.pid.reset();
+    }
+
+    /// Trims a `tank` command, returning the duties for the left and right wheel.
+    ///
+    /// Has to be called at a steady rate while driving straight, the integral
+    /// term takes care of a constant mismatch between the motors.
+    pub fn try_trim_at(
+        &mut self,
+        left: f32,
+        right: f32,
+        now: Instant,
+    ) -> Result<(f32, f32), Error> {
+        if left != right || left == 0.0 {
+            self.disengage();
+            return Ok((left, right));
+        }
+
+        let heading = self.source.try_heading()?;
+        let target = *self.target.get_or_insert(heading);
+        // counter-clockwise positive, so a heading below the target needs a faster right wheel
+        let trim = self.pid.update_at(target, heading, now);
+        Ok((left - trim, right + trim))
+    }
+
+    /// `DifferentialDrive::try_tank` with the trim applied.
+    pub fn try_tank_at<L: DcMotor, R: DcMotor>(
+        &mut self,
+        drive: &mut DifferentialDrive<L, R>,
+        left: f32,
+        right: f32,
+        now: Instant,
+    ) -> Result<(), Error> {
+        let (left, right) = self.try_trim_at(left, right, now)?;
+        drive.try_tank(left, right)
+    }
+}
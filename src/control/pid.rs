@@ -0,0 +1,165 @@
+use embassy_time::{Duration, Instant};
+
+/// How the integral term is kept from winding up while the output saturates.
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub enum AntiWindup {
+    None,
+    /// Stop integrating while the output is saturated in the direction of the error.
+    #[default]
+    Clamping,
+    /// Bleed the integral by `gain * (saturated - unsaturated)` output every update.
+    BackCalculation(f32),
+}
+
+/// PID controller with derivative-on-measurement and a first order filter on the D term.
+///
+/// Gains are per second: `ki` multiplies the error integrated over seconds,
+/// `kd` multiplies the rate of change of the measurement per second.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct Pid {
+    kp: f32,
+    ki: f32,
+    kd: f32,
+    out_min: f32,
+    out_max: f32,
+    integral_min: f32,
+    integral_max: f32,
+    anti_windup: AntiWindup,
+    d_filter_tau: f32, // seconds, 0.0 disables the filter
+    integral: f32,
+    derivative: f32,
+    prev_measurement: Option<f32>,
+    last_update: Option<Instant>,
+}
+
+impl Pid {
+    pub fn new(kp: f32, ki: f32, kd: f32) -> Self {
+        Self {
+            kp,
+            ki,
+            kd,
+            out_min: f32::NEG_INFINITY,
+            out_max: f32::INFINITY,
+            integral_min: f32::NEG_INFINITY,
+            integral_max: f32::INFINITY,
+            anti_windup: AntiWindup::default(),
+            d_filter_tau: 0.0,
+            integral: 0.0,
+            derivative: 0.0,
+            prev_measurement: None,
+            last_update: None,
+        }
+    }
+
+    /// A controller for `ki` and `kd` tuned per call of a loop running every
+    /// `period`, turned into the per second gains `update_at` works with.
+    pub fn from_iteration_gains(kp: f32, ki: f32, kd: f32, period: Duration) -> Self {
+        let seconds = period.as_micros() as f32 / 1_000_000.0;
+        Self::new(kp, ki / seconds, kd * seconds)
+    }
+
+    pub fn with_output_limits(mut self, min: f32, max: f32) -> Self {
+        self.set_output_limits(min, max);
+        self
+    }
+
+    /// Bounds what the integral term alone may add to the output.
+    pub fn with_integral_limits(mut self, min: f32, max: f32) -> Self {
+        debug_assert!(min <= max);
+        self.integral_min = min;
+        self.integral_max = max;
+        self
+    }
+
+    pub fn with_anti_windup(mut self, anti_windup: AntiWindup) -> Self {
+        self.anti_windup = anti_windup;
+        self
+    }
+
+    /// Time constant of the derivative low-pass filter in seconds.
+    pub fn with_derivative_filter(mut self, tau: f32) -> Self {
+        self.d_filter_tau = tau.max(0.0);
+        self
+    }
+
+    pub fn set_gains(&mut self, kp: f32, ki: f32, kd: f32) {
+        self.kp = kp;
+        self.ki = ki;
+        self.kd = kd;
+    }
+
+    pub fn set_output_limits(&mut self, min: f32, max: f32) {
+        debug_assert!(min <= max);
+        self.out_min = min;
+        self.out_max = max;
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.derivative = 0.0;
+        self.prev_measurement = None;
+        self.last_update = None;
+    }
+
+    /// Runs one step using the time elapsed since the previous call to `update_at`.
+    ///
+    /// The very first call has no elapsed time, so only the proportional term acts.
+    pub fn update_at(&mut self, setpoint: f32, measurement: f32, now: Instant) -> f32 {
+        let dt = match self.last_update {
+            Some(last) => now.saturating_duration_since(last).as_micros() as f32 / 1_000_000.0,
+            None => 0.0,
+        };
+        self.last_update = Some(now);
+        self.update(setpoint, measurement, dt)
+    }
+
+    /// Runs one step with an explicit time step in seconds.
+    pub fn update(&mut self, setpoint: f32, measurement: f32, dt: f32) -> f32 {
+        let error = setpoint - measurement;
+
+        if dt > 0.0
+            && let Some(prev) = self.prev_measurement
+        {
+            let raw = -(measurement - prev) / dt;
+            if self.d_filter_tau > 0.0 {
+                let alpha = dt / (self.d_filter_tau + dt);
+                self.derivative += alpha * (raw - self.derivative);
+            } else {
+                self.derivative = raw;
+            }
+        }
+        self.prev_measurement = Some(measurement);
+
+        let proportional = self.kp * error;
+        let derivative = self.kd * self.derivative;
+
+        let integral_step = self.ki * error * dt;
+        let integral = self.clamp_integral(self.integral + integral_step);
+        let unsaturated = proportional + integral + derivative;
+        let output = unsaturated.clamp(self.out_min, self.out_max);
+
+        match self.anti_windup {
+            AntiWindup::None => self.integral = integral,
+            AntiWindup::Clamping => {
+                let pushing_further = (unsaturated > self.out_max && integral_step > 0.0)
+                    || (unsaturated < self.out_min && integral_step < 0.0);
+                if !pushing_further {
+                    self.integral = integral;
+                }
+            }
+            AntiWindup::BackCalculation(gain) => {
+                self.integral = self.clamp_integral(integral + gain * (output - unsaturated) * dt);
+            }
+        }
+
+        output
+    }
+
+    fn clamp_integral(&self, integral: f32) -> f32 {
+        integral.clamp(self.integral_min, self.integral_max)
+    }
+}
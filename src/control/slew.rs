@@ -0,0 +1,74 @@
+use embassy_time::Instant;
+
+/// Limits how fast a speed command in percent may change.
+///
+/// A command that changes direction first ramps down to zero at the reversal
+/// rate and only then speeds up the other way, so the motor never jumps
+/// straight from forward to backward.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct SlewLimiter {
+    max_accel: f32,    // percent per second
+    max_reversal: f32, // percent per second while slowing down for a reversal
+    current: f32,
+    last_update: Option<Instant>,
+}
+
+impl SlewLimiter {
+    /// Rates are in percent per second, `f32::INFINITY` disables the limit.
+    pub fn new(max_accel: f32, max_reversal: f32) -> Self {
+        Self {
+            max_accel,
+            max_reversal,
+            current: 0.0,
+            last_update: None,
+        }
+    }
+
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    /// Jumps to `speed` without ramping, e.g. after an emergency stop.
+    pub fn reset(&mut self, speed: f32) {
+        self.current = speed;
+        self.last_update = None;
+    }
+
+    /// Steps towards `target` by the time elapsed since the previous call.
+    ///
+    /// The first call has no elapsed time and keeps the current value.
+    pub fn update_at(&mut self, target: f32, now: Instant) -> f32 {
+        let dt = match self.last_update {
+            Some(last) => now.saturating_duration_since(last).as_micros() as f32 / 1_000_000.0,
+            None => 0.0,
+        };
+        self.last_update = Some(now);
+        self.update(target, dt)
+    }
+
+    /// Steps towards `target` over `dt` seconds.
+    pub fn update(&mut self, target: f32, dt: f32) -> f32 {
+        let reversing =
+            self.current != 0.0 && target != 0.0 && (self.current > 0.0) != (target > 0.0);
+
+        // an unlimited rate must not turn into NaN when dt is zero
+        let step = |rate: f32| if rate.is_infinite() { rate } else { rate * dt };
+
+        self.current = if reversing {
+            approach(self.current, 0.0, step(self.max_reversal))
+        } else {
+            approach(self.current, target, step(self.max_accel))
+        };
+        self.current
+    }
+}
+
+fn approach(from: f32, to: f32, max_step: f32) -> f32 {
+    if (to - from).abs() <= max_step {
+        to
+    } else if to > from {
+        from + max_step
+    } else {
+        from - max_step
+    }
+}
@@ -0,0 +1,162 @@
+use core::convert::Infallible;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_time::{Duration, Instant, Ticker};
+
+use crate::control::Pid;
+use crate::drivers::encoder::{Encoder, WheelEncoder};
+use crate::drivers::motor::DcMotor;
+use crate::error::Error;
+
+/// Open-loop duty for a wheel speed: `static_duty + duty_per_mm_s * speed`.
+///
+/// Measured at one battery voltage, the PI loop corrects what it gets wrong at any other.
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct Feedforward {
+    /// Duty in percent the wheel needs to start turning.
+    pub static_duty: f32,
+    pub duty_per_mm_s: f32,
+}
+
+impl Default for Feedforward {
+    /// No feedforward, the PI loop does all the work.
+    fn default() -> Self {
+        Self {
+            static_duty: 0.0,
+            duty_per_mm_s: 0.0,
+        }
+    }
+}
+
+impl Feedforward {
+    /// Fits the line through two measured `(duty, speed_mm_s)` points, both
+    /// driving forward at different duties.
+    ///
+    /// `None` unless `high` has both the higher duty and the higher speed.
+    pub fn from_points(low: (f32, f32), high: (f32, f32)) -> Option<Self> {
+        if !(high.0 > low.0 && high.1 > low.1) {
+            return None;
+        }
+        let duty_per_mm_s = (high.0 - low.0) / (high.1 - low.1);
+        Some(Self {
+            static_duty: low.0 - duty_per_mm_s * low.1,
+            duty_per_mm_s,
+        })
+    }
+
+    pub fn duty(&self, speed_mm_s: f32) -> f32 {
+        if speed_mm_s == 0.0 {
+            return 0.0;
+        }
+        self.static_duty.copysign(speed_mm_s) + self.duty_per_mm_s * speed_mm_s
+    }
+}
+
+/// A target speed written by one task and followed by a `VelocityMotor` in another.
+#[derive(Debug, Default)]
+pub struct TargetSpeed {
+    mm_s: AtomicU32, // f32 bits
+}
+
+impl TargetSpeed {
+    pub const fn new() -> Self {
+        Self {
+            mm_s: AtomicU32::new(0), // 0.0
+        }
+    }
+
+    pub fn set(&self, mm_s: f32) {
+        self.mm_s.store(mm_s.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.mm_s.load(Ordering::Relaxed))
+    }
+}
+
+/// A motor following a wheel speed in mm/s, measured by an encoder on the same wheel.
+///
+/// `try_update_at` has to run at a fixed rate, `run` does that with a `Ticker`.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct VelocityMotor<M: DcMotor, E: Encoder> {
+    motor: M,
+    wheel: WheelEncoder<E>,
+    pid: Pid,
+    feedforward: Feedforward,
+    target: f32,
+}
+
+impl<M: DcMotor, E: Encoder> VelocityMotor<M, E> {
+    pub fn new(motor: M, wheel: WheelEncoder<E>, feedforward: Feedforward) -> Self {
+        Self {
+            motor,
+            wheel,
+            // percent of duty per mm/s of error, suits a small gearmotor
+            pid: Pid::new(0.05, 2.0, 0.0),
+            feedforward,
+            target: 0.0,
+        }
+    }
+
+    /// PI gains in percent per mm/s, the output limits are managed by `update`.
+    pub fn with_pid(mut self, pid: Pid) -> Self {
+        self.pid = pid;
+        self
+    }
+
+    pub fn motor_mut(&mut self) -> &mut M {
+        &mut self.motor
+    }
+
+    pub fn wheel(&self) -> &WheelEncoder<E> {
+        &self.wheel
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    pub fn set_target(&mut self, mm_s: f32) {
+        self.target = mm_s;
+    }
+
+    pub fn speed_mm_s(&self) -> f32 {
+        self.wheel.speed_mm_s()
+    }
+
+    /// Measures the wheel and sets the motor duty, returning the duty.
+    ///
+    /// A zero target stops the motor and clears the integral.
+    pub fn try_update_at(&mut self, now: Instant) -> Result<f32, Error> {
+        self.wheel.try_update_at(now)?;
+        let speed = self.wheel.speed_mm_s();
+
+        if self.target == 0.0 {
+            self.pid.reset();
+            self.motor.try_stop()?;
+            return Ok(0.0);
+        }
+
+        let feedforward = self.feedforward.duty(self.target);
+        // the correction may only use the duty the feedforward left
+        self.pid
+            .set_output_limits(-100.0 - feedforward, 100.0 - feedforward);
+        let duty = feedforward + self.pid.update_at(self.target, speed, now);
+        self.motor.try_run(duty)?;
+        Ok(duty)
+    }
+
+    /// Follows `target` forever, updating every `period`. Returns only on an error.
+    pub async fn run(
+        &mut self,
+        target: &TargetSpeed,
+        period: Duration,
+    ) -> Result<Infallible, Error> {
+        let mut ticker = Ticker::every(period);
+        loop {
+            ticker.next().await;
+            self.set_target(target.get());
+            self.try_update_at(Instant::now())?;
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use core::convert::Infallible;
+
+use embedded_hal::digital::OutputPin;
+use embedded_hal::pwm::SetDutyCycle;
+
+use crate::drivers::motor::{DcMotor, Motor};
+use crate::error::{Error, infallible};
+
+/// Physical layout of a two-wheel chassis, used by `try_twist` and `try_curvature`.
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct DriveGeometry {
+    /// Distance between the contact points of the two wheels.
+    pub track_width_mm: f32,
+    /// Wheel surface speed at 100% duty, only used to turn mm/s into percent.
+    pub max_wheel_speed_mm_s: f32,
+}
+
+impl Default for DriveGeometry {
+    /// Rough figures for a small gearmotor chassis, measure your own robot.
+    fn default() -> Self {
+        Self {
+            track_width_mm: 130.0,
+            max_wheel_speed_mm_s: 400.0,
+        }
+    }
+}
+
+/// Scales both sides down by the same factor when one exceeds 100%, so the
+/// ratio between them, i.e. the steering, is kept.
+pub fn desaturate(left: f32, right: f32) -> (f32, f32) {
+    let largest = left.abs().max(right.abs());
+    if largest > 100.0 {
+        let scale = 100.0 / largest;
+        (left * scale, right * scale)
+    } else {
+        (left, right)
+    }
+}
+
+/// Two motors driving the left and right wheels of the robot.
+///
+/// Speeds are in percent with positive driving the robot forward. Turns are
+/// counter-clockwise positive, seen from above, in every method.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct DifferentialDrive<L: DcMotor, R: DcMotor> {
+    left: L,
+    right: R,
+    geometry: DriveGeometry,
+}
+
+impl<L: DcMotor, R: DcMotor> DifferentialDrive<L, R> {
+    pub fn new(left: L, right: R) -> Self {
+        Self {
+            left,
+            right,
+            geometry: DriveGeometry::default(),
+        }
+    }
+
+    pub fn with_geometry(mut self, geometry: DriveGeometry) -> Self {
+        self.geometry = geometry;
+        self
+    }
+
+    pub fn geometry(&self) -> DriveGeometry {
+        self.geometry
+    }
+
+    pub fn left_mut(&mut self) -> &mut L {
+        &mut self.left
+    }
+
+    pub fn right_mut(&mut self) -> &mut R {
+        &mut self.right
+    }
+
+    pub fn into_inner(self) -> (L, R) {
+        (self.left, self.right)
+    }
+
+    /// Drives each side directly. Commands over 100% are desaturated to keep
+    /// their ratio, so callers can add full steering on top of full speed.
+    pub fn try_tank(&mut self, left: f32, right: f32) -> Result<(), Error> {
+        let (left, right) = desaturate(left, right);
+        // command both even if one fails
+        let left = self.left.try_run(left);
+        let right = self.right.try_run(right);
+        left.and(right)
+    }
+
+    /// `turn` is positive to the left (counter-clockwise seen from above), as in `try_twist`.
+    pub fn try_arcade(&mut self, throttle: f32, turn: f32) -> Result<(), Error> {
+        self.try_tank(throttle - turn, throttle + turn)
+    }
+
+    /// Drives along an arc of the given curvature (1 / radius in mm),
+    /// positive to the left as in `try_twist`. Zero curvature drives straight.
+    pub fn try_curvature(&mut self, speed: f32, curvature: f32) -> Result<(), Error> {
+        let half_track = curvature * self.geometry.track_width_mm / 2.0;
+        self.try_tank(speed * (1.0 - half_track), speed * (1.0 + half_track))
+    }
+
+    /// Body velocity command: forward speed in mm/s and yaw rate in rad/s,
+    /// counter-clockwise positive.
+    pub fn try_twist(&mut self, linear_mm_s: f32, angular_rad_s: f32) -> Result<(), Error> {
+        let (left, right) = self.wheel_speeds(linear_mm_s, angular_rad_s);
+        self.try_tank_mm_s(left, right)
+    }
+
+    /// `try_tank` with wheel speeds in mm/s, desaturated at `max_wheel_speed_mm_s`.
+    pub fn try_tank_mm_s(&mut self, left_mm_s: f32, right_mm_s: f32) -> Result<(), Error> {
+        let to_percent = 100.0 / self.geometry.max_wheel_speed_mm_s;
+        self.try_tank(left_mm_s * to_percent, right_mm_s * to_percent)
+    }
+
+    /// Left and right wheel speeds in mm/s for a body velocity.
+    pub fn wheel_speeds(&self, linear_mm_s: f32, angular_rad_s: f32) -> (f32, f32) {
+        let turn = angular_rad_s * self.geometry.track_width_mm / 2.0;
+        (linear_mm_s - turn, linear_mm_s + turn)
+    }
+
+    pub fn try_stop(&mut self) -> Result<(), Error> {
+        let left = self.left.try_stop();
+        let right = self.right.try_stop();
+        left.and(right)
+    }
+
+    pub fn try_brake(&mut self) -> Result<(), Error> {
+        let left = self.left.try_brake();
+        let right = self.right.try_brake();
+        left.and(right)
+    }
+
+    pub fn try_coast(&mut self) -> Result<(), Error> {
+        let left = self.left.try_coast();
+        let right = self.right.try_coast();
+        left.and(right)
+    }
+}
+
+/// Two `Motor`s on pins that cannot fail, the usual case with the STM32 HAL.
+impl<T1, U1, W1, T2, U2, W2> DifferentialDrive<Motor<T1, U1, W1>, Motor<T2, U2, W2>>
+where
+    T1: SetDutyCycle<Error = Infallible>,
+    U1: OutputPin<Error = Infallible>,
+    W1: OutputPin<Error = Infallible>,
+    T2: SetDutyCycle<Error = Infallible>,
+    U2: OutputPin<Error = Infallible>,
+    W2: OutputPin<Error = Infallible>,
+{
+    pub fn tank(&mut self, left: f32, right: f32) {
+        infallible(self.try_tank(left, right))
+    }
+
+    pub fn arcade(&mut self, throttle: f32, turn: f32) {
+        infallible(self.try_arcade(throttle, turn))
+    }
+
+    pub fn curvature(&mut self, speed: f32, curvature: f32) {
+        infallible(self.try_curvature(speed, curvature))
+    }
+
+    pub fn twist(&mut self, linear_mm_s: f32, angular_rad_s: f32) {
+        infallible(self.try_twist(linear_mm_s, angular_rad_s))
+    }
+
+    pub fn tank_mm_s(&mut self, left_mm_s: f32, right_mm_s: f32) {
+        infallible(self.try_tank_mm_s(left_mm_s, right_mm_s))
+    }
+
+    pub fn stop(&mut self) {
+        infallible(self.try_stop())
+    }
+
+    pub fn brake(&mut self) {
+        infallible(self.try_brake())
+    }
+
+    pub fn coast(&mut self) {
+        infallible(self.try_coast())
+    }
+}
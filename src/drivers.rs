@@ -1,3 +1,10 @@
+pub mod adc;
+pub mod encoder;
+pub mod hcsr04;
+pub mod imu;
 pub mod line_sensor;
 pub mod motor;
+pub mod range;
 pub mod servo;
+pub mod stepper;
+pub mod tof;
@@ -0,0 +1,50 @@
+use core::convert::Infallible;
+
+use embassy_stm32::adc::{Adc, AnyAdcChannel, Instance};
+use embedded_hal::digital::InputPin;
+
+use crate::error::Error;
+
+/// A group of `N` analog channels sampled together, in the spirit of the `embedded-hal` traits.
+pub trait AnalogInputs<const N: usize> {
+    type Error: Into<Error>;
+
+    fn try_read(&mut self) -> Result<[u16; N], Self::Error>;
+}
+
+/// `N` channels of one STM32 ADC, converted one after another.
+pub struct StmAdc<'d, T: Instance, const N: usize> {
+    adc: Adc<'d, T>,
+    channels: [AnyAdcChannel<T>; N],
+}
+
+impl<'d, T: Instance, const N: usize> StmAdc<'d, T, N> {
+    pub fn new(adc: Adc<'d, T>, channels: [AnyAdcChannel<T>; N]) -> Self {
+        Self { adc, channels }
+    }
+}
+
+impl<T: Instance, const N: usize> AnalogInputs<N> for StmAdc<'_, T, N> {
+    type Error = Infallible;
+
+    fn try_read(&mut self) -> Result<[u16; N], Self::Error> {
+        let mut values = [0; N];
+        for (value, channel) in values.iter_mut().zip(self.channels.iter_mut()) {
+            *value = self.adc.blocking_read(channel);
+        }
+        Ok(values)
+    }
+}
+
+/// Digital pins read as `1` when high and `0` when low, for calibrating digital line sensors.
+impl<P: InputPin, const N: usize> AnalogInputs<N> for [P; N] {
+    type Error = Error;
+
+    fn try_read(&mut self) -> Result<[u16; N], Self::Error> {
+        let mut values = [0; N];
+        for (value, pin) in values.iter_mut().zip(self.iter_mut()) {
+            *value = pin.is_high().map_err(Error::pin)? as u16;
+        }
+        Ok(values)
+    }
+}
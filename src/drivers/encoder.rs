@@ -0,0 +1,296 @@
+use core::convert::Infallible;
+use core::f32::consts::PI;
+use core::sync::atomic::{AtomicI32, Ordering};
+
+use embassy_futures::select::select;
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::timer::GeneralInstance4Channel;
+use embassy_stm32::timer::qei::Qei;
+use embassy_time::Instant;
+
+use crate::error::{Error, infallible};
+
+/// A wheel encoder counting signed ticks, forward positive, in the spirit of the `embedded-hal` traits.
+pub trait Encoder {
+    type Error: Into<Error>;
+
+    /// Ticks since the encoder was created.
+    fn try_ticks(&mut self) -> Result<i64, Self::Error>;
+}
+
+/// Extends a wrapping 16-bit hardware counter to 64 bits.
+///
+/// It has to be updated before the counter moves by half its range, 32768
+/// ticks, or the direction of the wrap is lost.
+#[derive(Debug, Default, Clone, Copy, defmt::Format)]
+pub struct WrappingCounter {
+    last_raw: Option<u16>,
+    total: i64,
+}
+
+impl WrappingCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts from `raw`, e.g. the hardware counter when the encoder is created.
+    pub fn starting_at(raw: u16) -> Self {
+        Self {
+            last_raw: Some(raw),
+            total: 0,
+        }
+    }
+
+    pub fn update(&mut self, raw: u16) -> i64 {
+        if let Some(last) = self.last_raw {
+            self.total += raw.wrapping_sub(last) as i16 as i64;
+        }
+        self.last_raw = Some(raw);
+        self.total
+    }
+
+    pub fn total(&self) -> i64 {
+        self.total
+    }
+}
+
+/// STM32 timer in encoder mode, counting all four edges of both channels in hardware.
+pub struct TimerEncoder<'d, T: GeneralInstance4Channel> {
+    qei: Qei<'d, T>,
+    counter: WrappingCounter,
+    inverted: bool,
+}
+
+impl<'d, T: GeneralInstance4Channel> TimerEncoder<'d, T> {
+    pub fn new(qei: Qei<'d, T>) -> Self {
+        Self {
+            counter: WrappingCounter::starting_at(qei.count()),
+            qei,
+            inverted: false,
+        }
+    }
+
+    /// For the encoder of a mirrored wheel, counting up when the robot drives forward.
+    pub fn new_invert(qei: Qei<'d, T>) -> Self {
+        Self {
+            inverted: true,
+            ..Self::new(qei)
+        }
+    }
+}
+
+impl<T: GeneralInstance4Channel> Encoder for TimerEncoder<'_, T> {
+    type Error = Infallible;
+
+    fn try_ticks(&mut self) -> Result<i64, Self::Error> {
+        let ticks = self.counter.update(self.qei.count());
+        Ok(if self.inverted { -ticks } else { ticks })
+    }
+}
+
+/// Quadrature state machine, counting every edge of both channels.
+#[derive(Debug, Default, Clone, Copy, defmt::Format)]
+pub struct QuadratureDecoder {
+    state: u8,
+}
+
+impl QuadratureDecoder {
+    pub fn new(a: bool, b: bool) -> Self {
+        Self {
+            state: Self::encode(a, b),
+        }
+    }
+
+    fn encode(a: bool, b: bool) -> u8 {
+        ((a as u8) << 1) | b as u8
+    }
+
+    /// Ticks moved since the last update: +1 when A leads B, -1 the other way,
+    /// 0 for no change or a skipped state that cannot be told apart.
+    pub fn update(&mut self, a: bool, b: bool) -> i8 {
+        // Gray code order 00 -> 10 -> 11 -> 01 counts up
+        const STEPS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+        let state = Self::encode(a, b);
+        let step = STEPS[((self.state << 2) | state) as usize];
+        self.state = state;
+        step
+    }
+}
+
+/// Tick count shared between an `ExtiEncoder` task and its readers.
+///
+/// The count wraps after 2^31 ticks and is extended to 64 bits by `EncoderCount`.
+#[derive(Debug, Default)]
+pub struct SharedTicks {
+    ticks: AtomicI32,
+}
+
+impl SharedTicks {
+    pub const fn new() -> Self {
+        Self {
+            ticks: AtomicI32::new(0),
+        }
+    }
+
+    pub fn add(&self, ticks: i32) {
+        self.ticks.fetch_add(ticks, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i32 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    /// A reader implementing `Encoder`.
+    pub fn reader(&self) -> EncoderCount<'_> {
+        EncoderCount {
+            shared: self,
+            last: self.get(),
+            total: 0,
+        }
+    }
+}
+
+/// Reads the ticks counted into a `SharedTicks`.
+#[derive(Debug)]
+pub struct EncoderCount<'a> {
+    shared: &'a SharedTicks,
+    last: i32,
+    total: i64,
+}
+
+impl Encoder for EncoderCount<'_> {
+    type Error = Infallible;
+
+    fn try_ticks(&mut self) -> Result<i64, Self::Error> {
+        let now = self.shared.get();
+        self.total += now.wrapping_sub(self.last) as i64;
+        self.last = now;
+        Ok(self.total)
+    }
+}
+
+/// Encoder decoded in software from EXTI interrupts on both channels, for
+/// pins without a timer in encoder mode.
+///
+/// `run` has to be polled from its own task, fast encoders will miss edges
+/// once the interrupt latency exceeds the time between them.
+pub struct ExtiEncoder<'d> {
+    a: ExtiInput<'d>,
+    b: ExtiInput<'d>,
+    decoder: QuadratureDecoder,
+    direction: i32,
+}
+
+impl<'d> ExtiEncoder<'d> {
+    pub fn new(a: ExtiInput<'d>, b: ExtiInput<'d>) -> Self {
+        let decoder = QuadratureDecoder::new(a.is_high(), b.is_high());
+        Self {
+            a,
+            b,
+            decoder,
+            direction: 1,
+        }
+    }
+
+    /// For the encoder of a mirrored wheel, counting up when the robot drives forward.
+    pub fn new_invert(a: ExtiInput<'d>, b: ExtiInput<'d>) -> Self {
+        Self {
+            direction: -1,
+            ..Self::new(a, b)
+        }
+    }
+
+    pub async fn run(&mut self, ticks: &SharedTicks) -> ! {
+        loop {
+            select(self.a.wait_for_any_edge(), self.b.wait_for_any_edge()).await;
+            let step = self.decoder.update(self.a.is_high(), self.b.is_high());
+            if step != 0 {
+                ticks.add(step as i32 * self.direction);
+            }
+        }
+    }
+}
+
+/// Wheel size and encoder resolution, for converting ticks into distance.
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct WheelGeometry {
+    /// Ticks per wheel revolution, after the gearbox and counting every edge.
+    pub counts_per_rev: f32,
+    pub wheel_diameter_mm: f32,
+}
+
+impl WheelGeometry {
+    pub fn mm_per_tick(&self) -> f32 {
+        PI * self.wheel_diameter_mm / self.counts_per_rev
+    }
+}
+
+/// An encoder on a wheel, tracking its speed between updates.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct WheelEncoder<E: Encoder> {
+    encoder: E,
+    geometry: WheelGeometry,
+    ticks: i64,
+    speed: f32, // ticks per second
+    last_update: Option<Instant>,
+}
+
+impl<E: Encoder> WheelEncoder<E> {
+    pub fn new(encoder: E, geometry: WheelGeometry) -> Self {
+        Self {
+            encoder,
+            geometry,
+            ticks: 0,
+            speed: 0.0,
+            last_update: None,
+        }
+    }
+
+    pub fn encoder_mut(&mut self) -> &mut E {
+        &mut self.encoder
+    }
+
+    pub fn geometry(&self) -> WheelGeometry {
+        self.geometry
+    }
+
+    /// Reads the encoder and estimates the speed over the time since the previous update.
+    ///
+    /// Call it at a steady rate: too often and a slow wheel reads as stopping
+    /// and starting, too rarely and the speed lags behind.
+    pub fn try_update_at(&mut self, now: Instant) -> Result<f32, Error> {
+        let ticks = self.encoder.try_ticks().map_err(Into::into)?;
+        if let Some(last) = self.last_update {
+            let dt = now.saturating_duration_since(last).as_micros() as f32 / 1_000_000.0;
+            if dt > 0.0 {
+                self.speed = (ticks - self.ticks) as f32 / dt;
+            }
+        }
+        self.ticks = ticks;
+        self.last_update = Some(now);
+        Ok(self.speed)
+    }
+
+    /// Ticks at the last update.
+    pub fn ticks(&self) -> i64 {
+        self.ticks
+    }
+
+    pub fn distance_mm(&self) -> f32 {
+        self.ticks as f32 * self.geometry.mm_per_tick()
+    }
+
+    pub fn speed_ticks_s(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn speed_mm_s(&self) -> f32 {
+        self.speed * self.geometry.mm_per_tick()
+    }
+}
+
+impl<E: Encoder<Error = Infallible>> WheelEncoder<E> {
+    pub fn update_at(&mut self, now: Instant) -> f32 {
+        infallible(self.try_update_at(now))
+    }
+}
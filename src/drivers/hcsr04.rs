@@ -0,0 +1,112 @@
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal_async::digital::Wait;
+
+use crate::drivers::range::{Range, RangeError, RangeSensor};
+use crate::error::Error;
+
+/// The echo has to start this soon after the trigger pulse.
+const ECHO_START_TIMEOUT: Duration = Duration::from_millis(10);
+/// The sensor gives up after about 38 ms without an echo and ends the pulse anyway.
+const NO_ECHO_PULSE: Duration = Duration::from_millis(36);
+
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct Hcsr04Config {
+    /// Air temperature, the speed of sound changes by about 0.2 % per degree.
+    pub temperature_c: f32,
+    pub min_range_mm: f32,
+    pub max_range_mm: f32,
+}
+
+impl Default for Hcsr04Config {
+    /// The range from the datasheet at room temperature.
+    fn default() -> Self {
+        Self {
+            temperature_c: 22.0,
+            min_range_mm: 20.0,
+            max_range_mm: 4000.0,
+        }
+    }
+}
+
+impl Hcsr04Config {
+    /// Speed of sound in mm/µs.
+    pub fn speed_of_sound(&self) -> f32 {
+        0.3313 * libm::sqrtf(1.0 + self.temperature_c / 273.15)
+    }
+
+    /// The range for an echo pulse of `pulse` that ended at `timestamp`.
+    pub fn try_range(&self, pulse: Duration, timestamp: Instant) -> Result<Range, RangeError> {
+        if pulse >= NO_ECHO_PULSE {
+            return Err(RangeError::NoEcho);
+        }
+        // the pulse lasts for the way there and back
+        let distance_mm = pulse.as_micros() as f32 * self.speed_of_sound() / 2.0;
+        if distance_mm < self.min_range_mm {
+            return Err(RangeError::TooClose);
+        }
+        if distance_mm > self.max_range_mm {
+            return Err(RangeError::TooFar);
+        }
+        Ok(Range {
+            distance_mm,
+            timestamp,
+            quality: 1.0,
+        })
+    }
+}
+
+/// HC-SR04 ultrasonic distance sensor, timing the echo pulse with `embassy_time`.
+///
+/// The echo pin needs an interrupt, e.g. an `ExtiInput`. Leave about 60 ms
+/// between measurements so that late echoes of a ping die out.
+pub struct Hcsr04<T: OutputPin, E: InputPin + Wait> {
+    trigger: T,
+    echo: E,
+    config: Hcsr04Config,
+}
+
+impl<T: OutputPin, E: InputPin + Wait> Hcsr04<T, E> {
+    pub fn new(trigger: T, echo: E, config: Hcsr04Config) -> Self {
+        Self {
+            trigger,
+            echo,
+            config,
+        }
+    }
+
+    pub fn config(&self) -> Hcsr04Config {
+        self.config
+    }
+
+    pub fn set_temperature(&mut self, temperature_c: f32) {
+        self.config.temperature_c = temperature_c;
+    }
+}
+
+impl<T: OutputPin, E: InputPin + Wait> RangeSensor for Hcsr04<T, E> {
+    async fn try_measure(&mut self) -> Result<Range, RangeError> {
+        // still busy with the previous ping
+        if self.echo.is_high().map_err(Error::pin)? {
+            return Err(RangeError::Timeout);
+        }
+
+        self.trigger.set_high().map_err(Error::pin)?;
+        Timer::after_micros(10).await;
+        self.trigger.set_low().map_err(Error::pin)?;
+
+        with_timeout(ECHO_START_TIMEOUT, self.echo.wait_for_high())
+            .await
+            .map_err(|_| RangeError::Timeout)?
+            .map_err(Error::pin)?;
+        let start = Instant::now();
+        // a bit longer than the pulse the sensor sends when it hears nothing
+        with_timeout(NO_ECHO_PULSE * 2, self.echo.wait_for_low())
+            .await
+            .map_err(|_| RangeError::Timeout)?
+            .map_err(Error::pin)?;
+        let end = Instant::now();
+
+        self.config.try_range(end - start, end)
+    }
+}
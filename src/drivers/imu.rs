@@ -0,0 +1,315 @@
+use core::f32::consts::PI;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use defmt::debug;
+use embassy_time::{Duration, Instant};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::i2c::I2c;
+
+use crate::error::Error;
+use crate::nav::motion::HeadingSource;
+
+/// I2C address with AD0 low, `0x69` with AD0 high.
+pub const DEFAULT_ADDRESS: u8 = 0x68;
+
+mod reg {
+    pub const SMPLRT_DIV: u8 = 0x19;
+    pub const ACCEL_XOUT_H: u8 = 0x3B;
+    pub const PWR_MGMT_1: u8 = 0x6B;
+    pub const WHO_AM_I: u8 = 0x75;
+}
+
+const DEVICE_RESET: u8 = 0x80;
+const CLOCK_PLL_GYRO_X: u8 = 0x01;
+
+/// WHO_AM_I of the MPU-6050 and the register compatible MPU-6500, MPU-9250 and MPU-9255.
+const KNOWN_IDS: [u8; 4] = [0x68, 0x70, 0x71, 0x73];
+
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub enum GyroRange {
+    #[default]
+    Dps250,
+    Dps500,
+    Dps1000,
+    Dps2000,
+}
+
+impl GyroRange {
+    fn bits(&self) -> u8 {
+        (*self as u8) << 3
+    }
+
+    /// Raw counts per degree per second.
+    pub fn sensitivity(&self) -> f32 {
+        match self {
+            GyroRange::Dps250 => 131.0,
+            GyroRange::Dps500 => 65.5,
+            GyroRange::Dps1000 => 32.8,
+            GyroRange::Dps2000 => 16.4,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub enum AccelRange {
+    #[default]
+    G2,
+    G4,
+    G8,
+    G16,
+}
+
+impl AccelRange {
+    fn bits(&self) -> u8 {
+        (*self as u8) << 3
+    }
+
+    /// Raw counts per g.
+    pub fn sensitivity(&self) -> f32 {
+        16384.0 / (1 << *self as u8) as f32
+    }
+}
+
+/// Bandwidth of the digital low pass filter on both accelerometer and gyro.
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub enum Dlpf {
+    /// Filter off, the gyro samples at 8 kHz.
+    Hz260,
+    Hz184,
+    Hz94,
+    #[default]
+    Hz44,
+    Hz21,
+    Hz10,
+    Hz5,
+}
+
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct ImuConfig {
+    pub gyro_range: GyroRange,
+    pub accel_range: AccelRange,
+    pub dlpf: Dlpf,
+    /// Sample rate is 1 kHz / (1 + divider) with the filter on.
+    pub sample_rate_divider: u8,
+}
+
+impl Default for ImuConfig {
+    /// ±250°/s and ±2 g at 100 Hz, plenty for a small robot.
+    fn default() -> Self {
+        Self {
+            gyro_range: GyroRange::default(),
+            accel_range: AccelRange::default(),
+            dlpf: Dlpf::default(),
+            sample_rate_divider: 9,
+        }
+    }
+}
+
+/// One burst read of all sensors, in the chip's axes.
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub struct ImuReading {
+    pub accel_g: [f32; 3],
+    /// Rate of turn in rad/s with the gyro bias removed, counter-clockwise positive.
+    pub gyro_rad_s: [f32; 3],
+    pub temperature_c: f32,
+}
+
+/// MPU-6050 class 6-axis IMU on an async I2C bus.
+///
+/// Yaw is the integrated rate around the chip's z axis, mount it flat with
+/// z pointing up. It drifts slowly even with a calibrated bias.
+#[derive(Debug, Clone, defmt::Format)]
+pub struct Mpu6050<I: I2c> {
+    i2c: I,
+    address: u8,
+    config: ImuConfig,
+    gyro_bias: [f32; 3], // rad/s
+    yaw: f32,
+    last_update: Option<Instant>,
+}
+
+impl<I: I2c> Mpu6050<I> {
+    pub fn new(i2c: I) -> Self {
+        Self {
+            i2c,
+            address: DEFAULT_ADDRESS,
+            config: ImuConfig::default(),
+            gyro_bias: [0.0; 3],
+            yaw: 0.0,
+            last_update: None,
+        }
+    }
+
+    pub fn with_address(mut self, address: u8) -> Self {
+        self.address = address;
+        self
+    }
+
+    pub fn with_config(mut self, config: ImuConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn i2c_mut(&mut self) -> &mut I {
+        &mut self.i2c
+    }
+
+    pub fn config(&self) -> ImuConfig {
+        self.config
+    }
+
+    pub fn gyro_bias(&self) -> [f32; 3] {
+        self.gyro_bias
+    }
+
+    pub fn set_gyro_bias(&mut self, bias: [f32; 3]) {
+        self.gyro_bias = bias;
+    }
+
+    /// Checks the chip id, resets the chip, wakes it up and applies the config.
+    pub async fn try_init<D: DelayNs>(&mut self, delay: &mut D) -> Result<(), Error> {
+        let mut id = [0];
+        self.read_registers(reg::WHO_AM_I, &mut id).await?;
+        if !KNOWN_IDS.contains(&id[0]) {
+            return Err(Error::UnknownDevice(id[0]));
+        }
+
+        self.write(&[reg::PWR_MGMT_1, DEVICE_RESET]).await?;
+        delay.delay_ms(100).await;
+        self.write(&[reg::PWR_MGMT_1, CLOCK_PLL_GYRO_X]).await?;
+
+        let config = self.config;
+        // SMPLRT_DIV, CONFIG, GYRO_CONFIG and ACCEL_CONFIG follow each other
+        self.write(&[
+            reg::SMPLRT_DIV,
+            config.sample_rate_divider,
+            config.dlpf as u8,
+            config.gyro_range.bits(),
+            config.accel_range.bits(),
+        ])
+        .await?;
+        debug!("imu {=u8:#x} ready, {}", id[0], config);
+        Ok(())
+    }
+
+    /// Accelerometer, temperature and gyro in that order, as the chip sends them.
+    pub async fn try_read_raw(&mut self) -> Result<[i16; 7], Error> {
+        let mut buf = [0; 14];
+        self.read_registers(reg::ACCEL_XOUT_H, &mut buf).await?;
+        let mut raw = [0; 7];
+        for (value, bytes) in raw.iter_mut().zip(buf.chunks_exact(2)) {
+            *value = i16::from_be_bytes([bytes[0], bytes[1]]);
+        }
+        Ok(raw)
+    }
+
+    pub async fn try_read(&mut self) -> Result<ImuReading, Error> {
+        let raw = self.try_read_raw().await?;
+        let accel_scale = self.config.accel_range.sensitivity();
+        let gyro_scale = self.config.gyro_range.sensitivity() * 180.0 / PI;
+        let mut reading = ImuReading {
+            temperature_c: raw[3] as f32 / 340.0 + 36.53,
+            ..Default::default()
+        };
+        for axis in 0..3 {
+            reading.accel_g[axis] = raw[axis] as f32 / accel_scale;
+            reading.gyro_rad_s[axis] = raw[axis + 4] as f32 / gyro_scale - self.gyro_bias[axis];
+        }
+        Ok(reading)
+    }
+
+    /// Averages `samples` gyro readings taken `interval` apart as the new bias.
+    ///
+    /// The robot must stand still the whole time, any motion ends up in the bias.
+    /// On a bus error, or with no samples at all, the previous bias is kept.
+    pub async fn try_calibrate_gyro<D: DelayNs>(
+        &mut self,
+        samples: u16,
+        interval: Duration,
+        delay: &mut D,
+    ) -> Result<[f32; 3], Error> {
+        if samples == 0 {
+            return Ok(self.gyro_bias);
+        }
+        let previous = core::mem::replace(&mut self.gyro_bias, [0.0; 3]);
+        let mut sum = [0.0; 3];
+        for _ in 0..samples {
+            let reading = match self.try_read().await {
+                Ok(reading) => reading,
+                Err(err) => {
+                    self.gyro_bias = previous;
+                    return Err(err);
+                }
+            };
+            for (sum, rate) in sum.iter_mut().zip(reading.gyro_rad_s) {
+                *sum += rate;
+            }
+            delay.delay_us(interval.as_micros() as u32).await;
+        }
+        self.gyro_bias = sum.map(|sum| sum / samples as f32);
+        debug!("gyro bias {} rad/s", self.gyro_bias);
+        Ok(self.gyro_bias)
+    }
+
+    /// Reads the gyro and integrates the yaw rate over the time since the previous update.
+    pub async fn try_update_yaw_at(&mut self, now: Instant) -> Result<f32, Error> {
+        let rate = self.try_read().await?.gyro_rad_s[2];
+        if let Some(last) = self.last_update {
+            let dt = now.saturating_duration_since(last).as_micros() as f32 / 1_000_000.0;
+            self.yaw += rate * dt;
+        }
+        self.last_update = Some(now);
+        Ok(self.yaw)
+    }
+
+    /// Integrated yaw in radians, counter-clockwise positive and not wrapped.
+    pub fn yaw(&self) -> f32 {
+        self.yaw
+    }
+
+    pub fn set_yaw(&mut self, yaw: f32) {
+        self.yaw = yaw;
+    }
+
+    async fn read_registers(&mut self, first: u8, buf: &mut [u8]) -> Result<(), Error> {
+        self.i2c
+            .write_read(self.address, &[first], buf)
+            .await
+            .map_err(Error::i2c)
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.i2c
+            .write(self.address, bytes)
+            .await
+            .map_err(Error::i2c)
+    }
+}
+
+/// Yaw published by the task that owns the IMU, for `Motion` and others to read.
+#[derive(Debug, Default)]
+pub struct SharedHeading {
+    yaw: AtomicU32, // f32 bits
+}
+
+impl SharedHeading {
+    pub const fn new() -> Self {
+        Self {
+            yaw: AtomicU32::new(0), // 0.0
+        }
+    }
+
+    pub fn set(&self, yaw: f32) {
+        self.yaw.store(yaw.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.yaw.load(Ordering::Relaxed))
+    }
+}
+
+impl HeadingSource for &SharedHeading {
+    fn try_heading(&mut self) -> Result<f32, Error> {
+        Ok(self.get())
+    }
+}
@@ -1,6 +1,16 @@
+use core::convert::Infallible;
+
 use defmt::debug;
+use embassy_time::Duration;
 use embedded_hal::digital::InputPin;
 
+use crate::drivers::adc::AnalogInputs;
+use crate::error::{Error, infallible};
+
+/// Period of the line following loops in the binaries, measured on the robot,
+/// that their PID gains were tuned at one iteration at a time.
+pub const LINE_LOOP_PERIOD: Duration = Duration::from_micros(100);
+
 #[derive(Debug, Default, Clone, Copy, defmt::Format)]
 pub struct LineSensor<T: InputPin> {
     pin: T,
@@ -15,15 +25,24 @@ impl<T: InputPin> LineSensor<T> {
         Self { pin, invert: true }
     }
 
-    pub fn is_on_line(&mut self) -> bool {
+    pub fn try_is_on_line(&mut self) -> Result<bool, Error> {
         if self.invert {
-            self.pin.is_low().unwrap()
+            self.pin.is_low().map_err(Error::pin)
         } else {
-            self.pin.is_high().unwrap()
+            self.pin.is_high().map_err(Error::pin)
         }
     }
 }
 
+impl<T: InputPin<Error = Infallible>> LineSensor<T> {
+    pub fn is_on_line(&mut self) -> bool {
+        infallible(self.try_is_on_line())
+    }
+}
+
+/// Distance between neighbouring sensors used when none is given.
+pub const DEFAULT_SPACING_MM: f32 = 10.0;
+
 #[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
 pub enum LinePos {
     #[default]
@@ -35,11 +54,122 @@ pub enum LinePos {
     Righter,
 }
 
+/// One snapshot of a row of line sensors, index 0 being the leftmost sensor.
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub struct LineReading {
+    /// Line position relative to the centre of the row, negative is left.
+    pub position_mm: f32,
+    /// Bit `i` is set when sensor `i` sees the line.
+    pub mask: u32,
+    pub active: u8,
+    pub all_on: bool,
+    pub none_on: bool,
+    /// Distance from the centre of the row to the outermost sensor.
+    pub half_width_mm: f32,
+}
+
+impl LineReading {
+    pub fn from_states(states: &[bool], spacing_mm: f32) -> Self {
+        debug_assert!(states.len() <= 32);
+        let mut mask = 0;
+        let mut active = 0;
+        let mut sum = 0;
+        for (idx, _) in states.iter().enumerate().filter(|(_, on)| **on) {
+            mask |= 1 << idx;
+            active += 1;
+            sum += idx;
+        }
+
+        let middle = states.len().saturating_sub(1) as f32 / 2.0;
+        let position_mm = if active == 0 {
+            0.0
+        } else {
+            (sum as f32 / active as f32 - middle) * spacing_mm
+        };
+
+        Self {
+            position_mm,
+            mask,
+            active,
+            all_on: active as usize == states.len(),
+            none_on: active == 0,
+            half_width_mm: middle * spacing_mm,
+        }
+    }
+
+    /// True when the active sensors form one uninterrupted run.
+    pub fn is_contiguous(&self) -> bool {
+        let run = self.mask >> self.mask.trailing_zeros().min(31);
+        run & run.wrapping_add(1) == 0
+    }
+
+    /// Coarse classification of the reading; crossings, gaps and lost lines are `NoLine`.
+    pub fn line_pos(&self) -> LinePos {
+        if self.none_on || self.all_on || !self.is_contiguous() || self.half_width_mm <= 0.0 {
+            return LinePos::NoLine;
+        }
+
+        let x = self.position_mm / self.half_width_mm;
+        if x <= -0.75 {
+            LinePos::Lefter
+        } else if x < -0.25 {
+            LinePos::Left
+        } else if x <= 0.25 {
+            LinePos::Middle
+        } else if x < 0.75 {
+            LinePos::Right
+        } else {
+            LinePos::Righter
+        }
+    }
+}
+
+/// A row of `N` identical line sensors with a fixed spacing between them.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct LineArray<P: InputPin, const N: usize> {
+    sensors: [LineSensor<P>; N],
+    spacing_mm: f32,
+}
+
+impl<P: InputPin, const N: usize> LineArray<P, N> {
+    pub fn new(sensors: [LineSensor<P>; N], spacing_mm: f32) -> Self {
+        const { assert!(N <= 32, "the reading mask holds up to 32 sensors") };
+        Self {
+            sensors,
+            spacing_mm,
+        }
+    }
+
+    pub fn spacing_mm(&self) -> f32 {
+        self.spacing_mm
+    }
+
+    pub fn try_read(&mut self) -> Result<LineReading, Error> {
+        let mut states = [false; N];
+        for (state, sensor) in states.iter_mut().zip(self.sensors.iter_mut()) {
+            *state = sensor.try_is_on_line()?;
+        }
+        let reading = LineReading::from_states(&states, self.spacing_mm);
+        debug!(
+            "line at {} mm, mask {:b}",
+            reading.position_mm, reading.mask
+        );
+        Ok(reading)
+    }
+}
+
+impl<P: InputPin<Error = Infallible>, const N: usize> LineArray<P, N> {
+    pub fn read(&mut self) -> LineReading {
+        infallible(self.try_read())
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, defmt::Format)]
 pub struct TrippleLineSensor<T: InputPin, U: InputPin, V: InputPin> {
     left: LineSensor<T>,
     middle: LineSensor<U>,
     right: LineSensor<V>,
+    spacing_mm: f32,
 }
 
 impl<T, U, V> TrippleLineSensor<T, U, V>
@@ -53,39 +183,225 @@ where
             left: LineSensor::new(left),
             middle: LineSensor::new(middle),
             right: LineSensor::new(right),
+            spacing_mm: DEFAULT_SPACING_MM,
         }
     }
 
+    pub fn with_spacing(mut self, spacing_mm: f32) -> Self {
+        self.spacing_mm = spacing_mm;
+        self
+    }
+
+    pub fn try_reading(&mut self) -> Result<LineReading, Error> {
+        let states = [
+            self.left.try_is_on_line()?,
+            self.middle.try_is_on_line()?,
+            self.right.try_is_on_line()?,
+        ];
+        Ok(LineReading::from_states(&states, self.spacing_mm))
+    }
+
+    pub fn try_read(&mut self) -> Result<LinePos, Error> {
+        let pos = self.try_reading()?.line_pos();
+        debug!("{}", pos);
+        Ok(pos)
+    }
+}
+
+impl<T, U, V> TrippleLineSensor<T, U, V>
+where
+    T: InputPin<Error = Infallible>,
+    U: InputPin<Error = Infallible>,
+    V: InputPin<Error = Infallible>,
+{
+    pub fn reading(&mut self) -> LineReading {
+        infallible(self.try_reading())
+    }
+
     pub fn read(&mut self) -> LinePos {
-        match (
-            self.left.is_on_line(),
-            self.middle.is_on_line(),
-            self.right.is_on_line(),
-        ) {
-            (true, false, false) => {
-                debug!("Lefter");
-                LinePos::Lefter
-            }
-            (true, true, false) => {
-                debug!("Left");
-                LinePos::Left
-            }
-            (false, true, false) => {
-                debug!("Middle");
-                LinePos::Middle
-            }
-            (false, true, true) => {
-                debug!("Right");
-                LinePos::Right
-            }
-            (false, false, true) => {
-                debug!("Righter");
-                LinePos::Righter
+        infallible(self.try_read())
+    }
+}
+
+/// Full scale of a calibrated analog reading.
+pub const CALIBRATED_MAX: u16 = 1000;
+
+/// Calibrated value above which a channel counts as seeing the line.
+const ON_LINE_THRESHOLD: u16 = 500;
+
+/// Calibrated value below which a channel is treated as noise in the weighted average.
+const NOISE_THRESHOLD: u16 = 50;
+
+/// Raw range seen by one analog channel, `min > max` until the first sample.
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct ChannelCalibration {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl Default for ChannelCalibration {
+    fn default() -> Self {
+        Self {
+            min: u16::MAX,
+            max: 0,
+        }
+    }
+}
+
+impl ChannelCalibration {
+    pub fn update(&mut self, raw: u16) {
+        self.min = self.min.min(raw);
+        self.max = self.max.max(raw);
+    }
+
+    /// Maps `raw` onto `0..=CALIBRATED_MAX`, or 0 when the channel was never calibrated.
+    pub fn normalise(&self, raw: u16) -> u16 {
+        if self.max <= self.min {
+            return 0;
+        }
+        let span = (self.max - self.min) as u32;
+        let offset = raw.clamp(self.min, self.max) - self.min;
+        (offset as u32 * CALIBRATED_MAX as u32 / span) as u16
+    }
+}
+
+/// A row of `N` analog reflectance sensors, index 0 being the leftmost one.
+///
+/// Raw values are expected to grow as less light is reflected, as with the QTR
+/// modules and TCRT5000 boards. The line position is the average of the sensor
+/// positions weighted by their calibrated values, like the Pololu QTR algorithm.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct AnalogLineArray<A: AnalogInputs<N>, const N: usize> {
+    inputs: A,
+    calibration: [ChannelCalibration; N],
+    spacing_mm: f32,
+    light_line: bool,
+    last_position_mm: f32,
+}
+
+impl<A: AnalogInputs<N>, const N: usize> AnalogLineArray<A, N> {
+    /// For a line that reflects less light than the field.
+    pub fn new(inputs: A, spacing_mm: f32) -> Self {
+        const { assert!(N <= 32, "the reading mask holds up to 32 sensors") };
+        Self {
+            inputs,
+            calibration: [ChannelCalibration::default(); N],
+            spacing_mm,
+            light_line: false,
+            last_position_mm: 0.0,
+        }
+    }
+
+    /// For a line that reflects more light than the field.
+    pub fn new_invert(inputs: A, spacing_mm: f32) -> Self {
+        Self {
+            light_line: true,
+            ..Self::new(inputs, spacing_mm)
+        }
+    }
+
+    pub fn inputs_mut(&mut self) -> &mut A {
+        &mut self.inputs
+    }
+
+    pub fn calibration(&self) -> &[ChannelCalibration; N] {
+        &self.calibration
+    }
+
+    pub fn set_calibration(&mut self, calibration: [ChannelCalibration; N]) {
+        self.calibration = calibration;
+    }
+
+    pub fn reset_calibration(&mut self) {
+        self.calibration = [ChannelCalibration::default(); N];
+    }
+
+    /// Samples every channel once and widens its min/max; call it repeatedly
+    /// while the sensors sweep over both the line and the field.
+    pub fn try_calibrate(&mut self) -> Result<(), Error> {
+        let raw = self.try_read_raw()?;
+        for (calibration, value) in self.calibration.iter_mut().zip(raw) {
+            calibration.update(value);
+        }
+        Ok(())
+    }
+
+    pub fn try_read_raw(&mut self) -> Result<[u16; N], Error> {
+        self.inputs.try_read().map_err(Into::into)
+    }
+
+    /// Calibrated values, `CALIBRATED_MAX` meaning "right over the line".
+    pub fn try_read_calibrated(&mut self) -> Result<[u16; N], Error> {
+        let raw = self.try_read_raw()?;
+        let mut values = [0; N];
+        for ((value, calibration), raw) in values.iter_mut().zip(&self.calibration).zip(raw) {
+            let normalised = calibration.normalise(raw);
+            *value = if self.light_line {
+                CALIBRATED_MAX - normalised
+            } else {
+                normalised
+            };
+        }
+        Ok(values)
+    }
+
+    /// When the line is lost the position sticks to the side it was last seen on.
+    pub fn try_read(&mut self) -> Result<LineReading, Error> {
+        let values = self.try_read_calibrated()?;
+        let middle = N.saturating_sub(1) as f32 / 2.0;
+        let half_width_mm = middle * self.spacing_mm;
+
+        let mut reading = LineReading {
+            half_width_mm,
+            ..Default::default()
+        };
+        let mut weighted = 0u32;
+        let mut total = 0u32;
+        for (idx, value) in values.iter().copied().enumerate() {
+            if value > ON_LINE_THRESHOLD {
+                reading.mask |= 1 << idx;
+                reading.active += 1;
             }
-            _ => {
-                debug!("NoLine");
-                LinePos::NoLine
+            if value > NOISE_THRESHOLD {
+                weighted += value as u32 * idx as u32;
+                total += value as u32;
             }
         }
+        reading.all_on = reading.active as usize == N;
+        reading.none_on = reading.active == 0;
+
+        reading.position_mm = if reading.none_on || total == 0 {
+            if self.last_position_mm < 0.0 {
+                -half_width_mm
+            } else if self.last_position_mm > 0.0 {
+                half_width_mm
+            } else {
+                0.0
+            }
+        } else {
+            (weighted as f32 / total as f32 - middle) * self.spacing_mm
+        };
+        self.last_position_mm = reading.position_mm;
+
+        debug!("line at {} mm, values {}", reading.position_mm, values);
+        Ok(reading)
+    }
+}
+
+impl<A: AnalogInputs<N, Error = Infallible>, const N: usize> AnalogLineArray<A, N> {
+    pub fn calibrate(&mut self) {
+        infallible(self.try_calibrate())
+    }
+
+    pub fn read_raw(&mut self) -> [u16; N] {
+        infallible(self.try_read_raw())
+    }
+
+    pub fn read_calibrated(&mut self) -> [u16; N] {
+        infallible(self.try_read_calibrated())
+    }
+
+    pub fn read(&mut self) -> LineReading {
+        infallible(self.try_read())
     }
 }
@@ -1,14 +1,76 @@
+use core::convert::Infallible;
+
 use defmt::debug;
-use embedded_hal::digital::OutputPin;
+use embassy_time::Instant;
+use embedded_hal::digital::{OutputPin, PinState};
 use embedded_hal::pwm::SetDutyCycle;
+use num_traits::float::FloatCore;
 
-#[derive(Debug, Default, Clone, Copy, defmt::Format)]
+use crate::control::SlewLimiter;
+use crate::error::{Error, infallible};
+
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
 pub enum Direction {
     #[default]
     Forward,
     Backward,
 }
 
+/// Maps a speed in percent onto the full duty cycle range, rounding to the nearest count.
+pub fn duty_for_speed(speed: f32, max_duty: u16) -> u16 {
+    let fraction = speed.clamp(0.0, 100.0) / 100.0;
+    (fraction * max_duty as f32).round() as u16
+}
+
+/// Per-motor output mapping: deadband compensation plus a gain to match the wheels.
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct MotorCalibration {
+    /// Duty in percent below which the motor does not turn at all.
+    pub min_duty: f32,
+    /// Multiplies every command, e.g. 0.95 for a motor that runs faster than its pair.
+    pub gain: f32,
+}
+
+impl Default for MotorCalibration {
+    fn default() -> Self {
+        Self {
+            min_duty: 0.0,
+            gain: 1.0,
+        }
+    }
+}
+
+impl MotorCalibration {
+    /// Maps a command magnitude in `0..=100` onto `min_duty..=100`, 0 staying 0.
+    pub fn apply(&self, speed: f32) -> f32 {
+        let scaled = (speed * self.gain).clamp(0.0, 100.0);
+        if scaled <= 0.0 {
+            return 0.0;
+        }
+        let min_duty = self.min_duty.clamp(0.0, 100.0);
+        min_duty + (100.0 - min_duty) * scaled / 100.0
+    }
+}
+
+/// H-bridge wiring with one PWM enable input and two direction inputs.
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub enum Bridge {
+    /// ENA/ENB is the PWM, the outputs float while it is low.
+    #[default]
+    L298n,
+    /// PWMA/PWMB is the PWM, IN1 = IN2 = high short-brakes and low floats the outputs.
+    Tb6612,
+}
+
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub enum StopMode {
+    /// Let the motor spin down freely.
+    #[default]
+    Coast,
+    /// Short the motor windings so it stops as fast as possible.
+    Brake,
+}
+
 #[derive(Debug, Default, Clone, Copy, defmt::Format)]
 pub struct Motor<T: SetDutyCycle, U: OutputPin, W: OutputPin> {
     pwm_pin: T,
@@ -16,6 +78,10 @@ pub struct Motor<T: SetDutyCycle, U: OutputPin, W: OutputPin> {
     backward_pin: W,
     speed: f32,
     direction: Direction,
+    bridge: Bridge,
+    stop_mode: StopMode,
+    calibration: MotorCalibration,
+    inverted: bool,
 }
 
 impl<T, U, W> Motor<T, U, W>
@@ -37,14 +103,65 @@ where
             backward_pin,
             speed,
             direction,
+            bridge: Bridge::default(),
+            stop_mode: StopMode::default(),
+            calibration: MotorCalibration::default(),
+            inverted: false,
+        }
+    }
+
+    /// For a motor wired backward: `run` with a positive speed still drives the robot forward.
+    pub fn new_invert(
+        pwm_pin: T,
+        forward_pin: U,
+        backward_pin: W,
+        speed: f32,
+        direction: Direction,
+    ) -> Self {
+        Self {
+            inverted: true,
+            ..Self::new(pwm_pin, forward_pin, backward_pin, speed, direction)
         }
     }
 
+    pub fn is_inverted(&self) -> bool {
+        self.inverted
+    }
+
+    pub fn with_calibration(mut self, calibration: MotorCalibration) -> Self {
+        self.calibration = calibration;
+        self
+    }
+
+    pub fn calibration(&self) -> MotorCalibration {
+        self.calibration
+    }
+
+    pub fn set_calibration(&mut self, calibration: MotorCalibration) {
+        self.calibration = calibration;
+    }
+
+    pub fn with_bridge(mut self, bridge: Bridge) -> Self {
+        self.bridge = bridge;
+        self
+    }
+
+    /// Mode used by `stop`.
+    pub fn with_stop_mode(mut self, stop_mode: StopMode) -> Self {
+        self.stop_mode = stop_mode;
+        self
+    }
+
+    pub fn stop_mode(&self) -> StopMode {
+        self.stop_mode
+    }
+
     pub fn set_dir(&mut self, dir: Direction) {
         debug!("direction = {}", self.direction);
         self.direction = dir;
     }
 
+    /// The direction the robot is driven in, whatever the wiring.
     pub fn get_dir(&mut self) -> Direction {
         self.direction
     }
@@ -54,14 +171,51 @@ where
         self.speed
     }
 
-    pub fn stop(&mut self) {
-        debug!("stop");
+    /// Smallest speed step in percent the PWM channel can produce.
+    pub fn resolution(&self) -> f32 {
+        100.0 / self.pwm_pin.max_duty_cycle().max(1) as f32
+    }
+
+    pub fn try_stop(&mut self) -> Result<(), Error> {
+        self.try_stop_with(self.stop_mode)
+    }
+
+    pub fn try_brake(&mut self) -> Result<(), Error> {
+        self.try_stop_with(StopMode::Brake)
+    }
+
+    pub fn try_coast(&mut self) -> Result<(), Error> {
+        self.try_stop_with(StopMode::Coast)
+    }
+
+    pub fn try_stop_with(&mut self, mode: StopMode) -> Result<(), Error> {
+        debug!("stop {}", mode);
         self.speed = 0.0;
-        let _ = self.pwm_pin.set_duty_cycle_fully_off();
-        let (_, _) = (self.forward_pin.set_low(), self.backward_pin.set_high());
+
+        let (inputs, pwm_on) = match (self.bridge, mode) {
+            (_, StopMode::Coast) => (PinState::Low, false),
+            (Bridge::L298n, StopMode::Brake) => (PinState::Low, true),
+            (Bridge::Tb6612, StopMode::Brake) => (PinState::High, false),
+        };
+
+        // try every pin even if one fails, a half stopped motor is worse
+        let mut set_inputs = || {
+            let forward = self.forward_pin.set_state(inputs).map_err(Error::pin);
+            let backward = self.backward_pin.set_state(inputs).map_err(Error::pin);
+            forward.and(backward)
+        };
+        // the inputs must match before the L298N gets enabled, or it drives for a moment
+        if pwm_on {
+            let inputs = set_inputs();
+            let pwm = self.pwm_pin.set_duty_cycle_fully_on().map_err(Error::pwm);
+            inputs.and(pwm)
+        } else {
+            let pwm = self.pwm_pin.set_duty_cycle_fully_off().map_err(Error::pwm);
+            pwm.and(set_inputs())
+        }
     }
 
-    pub fn run(&mut self, speed: f32) {
+    pub fn try_run(&mut self, speed: f32) -> Result<(), Error> {
         if speed < 0.0 {
             self.speed = -speed;
             self.set_dir(Direction::Backward);
@@ -74,12 +228,305 @@ where
             self.speed = 100.0;
         }
 
-        let (_, _) = match self.direction {
-            Direction::Forward => (self.forward_pin.set_high(), self.backward_pin.set_low()),
-            Direction::Backward => (self.forward_pin.set_low(), self.backward_pin.set_high()),
+        // the pins drive the wheel backward when the motor is wired the other way round
+        if (self.direction == Direction::Forward) != self.inverted {
+            self.forward_pin.set_high().map_err(Error::pin)?;
+            self.backward_pin.set_low().map_err(Error::pin)?;
+        } else {
+            self.forward_pin.set_low().map_err(Error::pin)?;
+            self.backward_pin.set_high().map_err(Error::pin)?;
+        }
+        let output = self.calibration.apply(self.speed);
+        let duty = duty_for_speed(output, self.pwm_pin.max_duty_cycle());
+        self.pwm_pin.set_duty_cycle(duty).map_err(Error::pwm)
+    }
+}
+
+impl<T, U, W> Motor<T, U, W>
+where
+    T: SetDutyCycle<Error = Infallible>,
+    U: OutputPin<Error = Infallible>,
+    W: OutputPin<Error = Infallible>,
+{
+    pub fn stop(&mut self) {
+        infallible(self.try_stop())
+    }
+
+    pub fn brake(&mut self) {
+        infallible(self.try_brake())
+    }
+
+    pub fn coast(&mut self) {
+        infallible(self.try_coast())
+    }
+
+    pub fn stop_with(&mut self, mode: StopMode) {
+        infallible(self.try_stop_with(mode))
+    }
+
+    pub fn run(&mut self, speed: f32) {
+        infallible(self.try_run(speed))
+    }
+}
+
+/// A DC motor driven through some kind of H-bridge, speed in percent, negative is backward.
+pub trait DcMotor {
+    fn try_run(&mut self, speed: f32) -> Result<(), Error>;
+
+    /// Stops with the motor's configured `StopMode`.
+    fn try_stop(&mut self) -> Result<(), Error>;
+
+    fn try_brake(&mut self) -> Result<(), Error>;
+
+    fn try_coast(&mut self) -> Result<(), Error>;
+}
+
+impl<T, U, W> DcMotor for Motor<T, U, W>
+where
+    T: SetDutyCycle,
+    U: OutputPin,
+    W: OutputPin,
+{
+    fn try_run(&mut self, speed: f32) -> Result<(), Error> {
+        Motor::try_run(self, speed)
+    }
+
+    fn try_stop(&mut self) -> Result<(), Error> {
+        Motor::try_stop(self)
+    }
+
+    fn try_brake(&mut self) -> Result<(), Error> {
+        Motor::try_brake(self)
+    }
+
+    fn try_coast(&mut self) -> Result<(), Error> {
+        Motor::try_coast(self)
+    }
+}
+
+/// How a two-PWM bridge spends the off part of the PWM period.
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub enum Decay {
+    /// The driven input is modulated, the motor coasts during the off time.
+    #[default]
+    Fast,
+    /// The other input is modulated, the motor brakes during the off time.
+    /// Gives a more linear speed response at low duty.
+    Slow,
+}
+
+/// Motor on a bridge where each input is its own PWM channel, like the DRV8833
+/// or the TB6612 with PWMx tied high.
+///
+/// IN1 = IN2 = low coasts, IN1 = IN2 = high brakes.
+#[derive(Debug, Default, Clone, Copy, defmt::Format)]
+pub struct DualPwmMotor<T: SetDutyCycle, U: SetDutyCycle> {
+    in1: T,
+    in2: U,
+    speed: f32,
+    direction: Direction,
+    decay: Decay,
+    stop_mode: StopMode,
+    calibration: MotorCalibration,
+    inverted: bool,
+}
+
+impl<T: SetDutyCycle, U: SetDutyCycle> DualPwmMotor<T, U> {
+    /// Positive speeds modulate `in1`.
+    pub fn new(in1: T, in2: U) -> Self {
+        Self {
+            in1,
+            in2,
+            speed: 0.0,
+            direction: Direction::default(),
+            decay: Decay::default(),
+            stop_mode: StopMode::default(),
+            calibration: MotorCalibration::default(),
+            inverted: false,
+        }
+    }
+
+    /// For a motor wired backward: `run` with a positive speed still drives the robot forward.
+    pub fn new_invert(in1: T, in2: U) -> Self {
+        Self {
+            inverted: true,
+            ..Self::new(in1, in2)
+        }
+    }
+
+    pub fn with_decay(mut self, decay: Decay) -> Self {
+        self.decay = decay;
+        self
+    }
+
+    /// Mode used by `stop`.
+    pub fn with_stop_mode(mut self, stop_mode: StopMode) -> Self {
+        self.stop_mode = stop_mode;
+        self
+    }
+
+    pub fn with_calibration(mut self, calibration: MotorCalibration) -> Self {
+        self.calibration = calibration;
+        self
+    }
+
+    /// The direction the robot is driven in, whatever the wiring.
+    pub fn get_dir(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn get_speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn try_stop_with(&mut self, mode: StopMode) -> Result<(), Error> {
+        debug!("stop {}", mode);
+        self.speed = 0.0;
+        // try both inputs even if one fails
+        let (in1, in2) = match mode {
+            StopMode::Coast => (
+                self.in1.set_duty_cycle_fully_off().map_err(Error::pwm),
+                self.in2.set_duty_cycle_fully_off().map_err(Error::pwm),
+            ),
+            StopMode::Brake => (
+                self.in1.set_duty_cycle_fully_on().map_err(Error::pwm),
+                self.in2.set_duty_cycle_fully_on().map_err(Error::pwm),
+            ),
+        };
+        in1.and(in2)
+    }
+
+    pub fn try_run(&mut self, speed: f32) -> Result<(), Error> {
+        self.direction = if speed < 0.0 {
+            Direction::Backward
+        } else {
+            Direction::Forward
         };
-        self.pwm_pin
-            .set_duty_cycle_percent(self.speed as u8)
-            .unwrap();
+        self.speed = speed.abs().min(100.0);
+        let output = self.calibration.apply(self.speed);
+
+        let in1_drives = (self.direction == Direction::Forward) != self.inverted;
+        let (driven, other) = match self.decay {
+            Decay::Fast => (output, 0.0),
+            Decay::Slow => (100.0, 100.0 - output),
+        };
+        let (in1, in2) = if in1_drives {
+            (driven, other)
+        } else {
+            (other, driven)
+        };
+
+        let in1 = duty_for_speed(in1, self.in1.max_duty_cycle());
+        let in2 = duty_for_speed(in2, self.in2.max_duty_cycle());
+        // set the smaller duty first so the inputs do not overlap on a direction change
+        if in1 < in2 {
+            self.in1.set_duty_cycle(in1).map_err(Error::pwm)?;
+            self.in2.set_duty_cycle(in2).map_err(Error::pwm)
+        } else {
+            self.in2.set_duty_cycle(in2).map_err(Error::pwm)?;
+            self.in1.set_duty_cycle(in1).map_err(Error::pwm)
+        }
+    }
+}
+
+impl<T: SetDutyCycle, U: SetDutyCycle> DcMotor for DualPwmMotor<T, U> {
+    fn try_run(&mut self, speed: f32) -> Result<(), Error> {
+        DualPwmMotor::try_run(self, speed)
+    }
+
+    fn try_stop(&mut self) -> Result<(), Error> {
+        self.try_stop_with(self.stop_mode)
+    }
+
+    fn try_brake(&mut self) -> Result<(), Error> {
+        self.try_stop_with(StopMode::Brake)
+    }
+
+    fn try_coast(&mut self) -> Result<(), Error> {
+        self.try_stop_with(StopMode::Coast)
+    }
+}
+
+/// A `Motor` whose speed commands are rate limited by a `SlewLimiter`.
+///
+/// `update` has to be called regularly, it moves the motor one step closer to
+/// the target. `emergency_stop` bypasses the ramp.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct RampedMotor<T: SetDutyCycle, U: OutputPin, W: OutputPin> {
+    motor: Motor<T, U, W>,
+    limiter: SlewLimiter,
+    target: f32,
+}
+
+impl<T, U, W> RampedMotor<T, U, W>
+where
+    T: SetDutyCycle,
+    U: OutputPin,
+    W: OutputPin,
+{
+    pub fn new(motor: Motor<T, U, W>, limiter: SlewLimiter) -> Self {
+        Self {
+            motor,
+            limiter,
+            target: 0.0,
+        }
+    }
+
+    pub fn motor(&self) -> &Motor<T, U, W> {
+        &self.motor
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    /// The speed currently applied, somewhere between the old and the new target.
+    pub fn current(&self) -> f32 {
+        self.limiter.current()
+    }
+
+    pub fn set_target(&mut self, speed: f32) {
+        self.target = speed.clamp(-100.0, 100.0);
+    }
+
+    pub fn try_update(&mut self, now: Instant) -> Result<f32, Error> {
+        let speed = self.limiter.update_at(self.target, now);
+        if speed == 0.0 {
+            self.motor.try_stop()?;
+        } else {
+            self.motor.try_run(speed)?;
+        }
+        Ok(speed)
+    }
+
+    pub fn try_run(&mut self, speed: f32, now: Instant) -> Result<f32, Error> {
+        self.set_target(speed);
+        self.try_update(now)
+    }
+
+    /// Stops right away with the given mode and drops the ramp state.
+    pub fn try_emergency_stop(&mut self, mode: StopMode) -> Result<(), Error> {
+        self.target = 0.0;
+        self.limiter.reset(0.0);
+        self.motor.try_stop_with(mode)
+    }
+}
+
+impl<T, U, W> RampedMotor<T, U, W>
+where
+    T: SetDutyCycle<Error = Infallible>,
+    U: OutputPin<Error = Infallible>,
+    W: OutputPin<Error = Infallible>,
+{
+    pub fn update(&mut self, now: Instant) -> f32 {
+        infallible(self.try_update(now))
+    }
+
+    pub fn run(&mut self, speed: f32, now: Instant) -> f32 {
+        infallible(self.try_run(speed, now))
+    }
+
+    pub fn emergency_stop(&mut self, mode: StopMode) {
+        infallible(self.try_emergency_stop(mode))
     }
 }
@@ -0,0 +1,81 @@
+pub mod filter;
+
+pub use filter::{RangeFilter, RangeFilterConfig, SharedRange};
+
+use embassy_time::Instant;
+
+use crate::error::Error;
+
+/// One distance measurement.
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct Range {
+    pub distance_mm: f32,
+    /// When the measurement was taken.
+    pub timestamp: Instant,
+    /// How far the reading can be trusted, from 0.0 to 1.0. Sensors that
+    /// cannot tell report 1.0 for every reading inside their range.
+    pub quality: f32,
+}
+
+/// Why a distance sensor has no distance to report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum RangeError {
+    /// The sensor did not respond, e.g. it is disconnected or still busy.
+    Timeout,
+    /// Something was measured, but closer than the sensor can be trusted.
+    TooClose,
+    /// Something was measured, but farther than the sensor can be trusted.
+    TooFar,
+    /// Nothing reflected the signal back.
+    NoEcho,
+    /// The pins or the bus of the sensor failed.
+    Driver(Error),
+}
+
+impl From<Error> for RangeError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Timeout => Self::Timeout,
+            err => Self::Driver(err),
+        }
+    }
+}
+
+/// A distance sensor measuring along one direction, in the spirit of the `embedded-hal-async` traits.
+#[allow(async_fn_in_trait)]
+pub trait RangeSensor {
+    /// Takes one measurement, waiting for it to finish.
+    async fn try_measure(&mut self) -> Result<Range, RangeError>;
+}
+
+impl<T: RangeSensor + ?Sized> RangeSensor for &mut T {
+    async fn try_measure(&mut self) -> Result<Range, RangeError> {
+        T::try_measure(self).await
+    }
+}
+
+/// Where a sensor takes the timestamps of its ranges from.
+pub trait Clock {
+    fn now(&mut self) -> Instant;
+}
+
+/// The `embassy_time` clock.
+#[derive(Debug, Default, Clone, Copy, defmt::Format)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&mut self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Room in front of the sensor according to a measurement: nothing echoing
+/// back, or an echo from beyond the range, counts as endlessly far. Something
+/// too close and a failed measurement count as no room at all.
+pub fn clearance_mm(measurement: &Result<Range, RangeError>) -> f32 {
+    match measurement {
+        Ok(range) => range.distance_mm,
+        Err(RangeError::NoEcho | RangeError::TooFar) => f32::INFINITY,
+        Err(RangeError::TooClose | RangeError::Timeout | RangeError::Driver(_)) => 0.0,
+    }
+}
@@ -0,0 +1,225 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_time::{Duration, Instant};
+
+use crate::drivers::range::{Range, RangeError, clearance_mm};
+
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct RangeFilterConfig {
+    /// Fastest the distance may change in mm/s, faster jumps are taken for spurious echoes.
+    pub max_rate_mm_s: f32,
+    /// Jumps rejected in a row before the filter takes the new distance as real,
+    /// e.g. something stepping in front of the sensor.
+    pub max_rejections: u8,
+    /// Weight of every new median in the exponential smoothing, 1.0 turns it off.
+    pub smoothing: f32,
+}
+
+impl Default for RangeFilterConfig {
+    /// Suits a sonar on a small robot, closing in at well under a metre per second.
+    fn default() -> Self {
+        Self {
+            max_rate_mm_s: 1000.0,
+            max_rejections: 2,
+            smoothing: 0.5,
+        }
+    }
+}
+
+/// Cleans up a stream of measurements: jumps faster than `max_rate_mm_s` are
+/// rejected, the rest go through a sliding median over the last `N` readings,
+/// at least one, and then exponential smoothing.
+///
+/// Failed measurements are dropped without touching the state, pass them on
+/// with `SharedRange::record` to tell a broken sensor from an empty view.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct RangeFilter<const N: usize> {
+    config: RangeFilterConfig,
+    window: [f32; N],
+    len: usize,
+    next: usize,
+    smoothed: Option<f32>,
+    last_accepted: Option<Range>,
+    rejections: u8,
+}
+
+impl<const N: usize> RangeFilter<N> {
+    pub fn new(config: RangeFilterConfig) -> Self {
+        const { assert!(N > 0) }
+        Self {
+            config,
+            window: [0.0; N],
+            len: 0,
+            next: 0,
+            smoothed: None,
+            last_accepted: None,
+            rejections: 0,
+        }
+    }
+
+    pub fn config(&self) -> RangeFilterConfig {
+        self.config
+    }
+
+    /// Forgets every reading, e.g. after pointing the sensor somewhere else.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.next = 0;
+        self.smoothed = None;
+        self.last_accepted = None;
+        self.rejections = 0;
+    }
+
+    /// Feeds one measurement, returning the filtered range unless it was
+    /// rejected or failed.
+    pub fn update(&mut self, measurement: Result<Range, RangeError>) -> Option<Range> {
+        let range = measurement.ok()?;
+
+        if let Some(last) = self.last_accepted {
+            let dt = range
+                .timestamp
+                .saturating_duration_since(last.timestamp)
+                .as_micros() as f32
+                / 1_000_000.0;
+            let jump = (range.distance_mm - last.distance_mm).abs();
+            if jump > self.config.max_rate_mm_s * dt {
+                if self.rejections < self.config.max_rejections {
+                    self.rejections += 1;
+                    return None;
+                }
+                // the jump persisted, start over from the new distance
+                self.reset();
+            }
+        }
+        self.rejections = 0;
+        self.last_accepted = Some(range);
+
+        self.window[self.next] = range.distance_mm;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+        let median = self.median();
+
+        let smoothed = match self.smoothed {
+            Some(smoothed) => smoothed + self.config.smoothing * (median - smoothed),
+            None => median,
+        };
+        self.smoothed = Some(smoothed);
+        Some(Range {
+            distance_mm: smoothed,
+            ..range
+        })
+    }
+
+    fn median(&self) -> f32 {
+        let mut sorted = self.window;
+        let sorted = &mut sorted[..self.len];
+        sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+        let middle = self.len / 2;
+        if self.len.is_multiple_of(2) {
+            (sorted[middle - 1] + sorted[middle]) / 2.0
+        } else {
+            sorted[middle]
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Latest {
+    range: Option<Range>,
+    /// The failure of the latest measurement, `None` once a range got through again.
+    error: Option<RangeError>,
+}
+
+/// The latest range written by the task that owns a sensor, for other tasks to read
+/// as long as it is recent enough, along with the latest failure.
+pub struct SharedRange<M: RawMutex> {
+    latest: Mutex<M, Cell<Latest>>,
+}
+
+impl<M: RawMutex> SharedRange<M> {
+    pub const fn new() -> Self {
+        Self {
+            latest: Mutex::new(Cell::new(Latest {
+                range: None,
+                error: None,
+            })),
+        }
+    }
+
+    pub fn publish(&self, range: Range) {
+        self.latest.lock(|latest| {
+            latest.set(Latest {
+                range: Some(range),
+                error: None,
+            })
+        });
+    }
+
+    /// Records the outcome of a measurement before it is filtered, keeping the
+    /// latest range until it goes stale. A failure counts until the next
+    /// measurement succeeds, even one the filter then rejects.
+    pub fn record(&self, measurement: &Result<Range, RangeError>) {
+        self.latest.lock(|latest| {
+            latest.set(Latest {
+                error: measurement.err(),
+                ..latest.get()
+            })
+        });
+    }
+
+    /// Forgets the latest range and failure, e.g. when the sensor is known to be broken.
+    pub fn clear(&self) {
+        self.latest.lock(|latest| {
+            latest.set(Latest {
+                range: None,
+                error: None,
+            })
+        });
+    }
+
+    /// The latest range if it was measured at most `max_age` before `now`,
+    /// `None` when there is no valid data.
+    pub fn fresh_at(&self, max_age: Duration, now: Instant) -> Option<Range> {
+        let latest = self.latest.lock(Cell::get).range?;
+        (now.saturating_duration_since(latest.timestamp) <= max_age).then_some(latest)
+    }
+
+    pub fn fresh(&self, max_age: Duration) -> Option<Range> {
+        self.fresh_at(max_age, Instant::now())
+    }
+
+    /// The failure of the latest measurement, `None` if it gave a range.
+    pub fn last_error(&self) -> Option<RangeError> {
+        self.latest.lock(Cell::get).error
+    }
+
+    /// Room in front of the sensor at `now`: the fresh range, or else what the
+    /// latest failure says according to `clearance_mm`. A range gone stale
+    /// without a failure to explain it leaves no room at all.
+    ///
+    /// `None` until the first measurement is published.
+    pub fn clearance_at(&self, max_age: Duration, now: Instant) -> Option<f32> {
+        if let Some(range) = self.fresh_at(max_age, now) {
+            return Some(range.distance_mm);
+        }
+        match self.latest.lock(Cell::get) {
+            Latest {
+                error: Some(error), ..
+            } => Some(clearance_mm(&Err(error))),
+            Latest { range: Some(_), .. } => Some(0.0),
+            Latest { .. } => None,
+        }
+    }
+
+    pub fn clearance(&self, max_age: Duration) -> Option<f32> {
+        self.clearance_at(max_age, Instant::now())
+    }
+}
+
+impl<M: RawMutex> Default for SharedRange<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,35 +1,328 @@
+use core::convert::Infallible;
+
+use embassy_time::{Delay, Duration};
 use embedded_hal::pwm::SetDutyCycle;
+use embedded_hal_async::delay::DelayNs;
+use num_traits::float::FloatCore;
+
+use crate::drivers::motor::DcMotor;
+use crate::error::{Error, infallible};
+
+/// Pulse timing and travel of a hobby servo.
+///
+/// The pulse is interpolated linearly from `min_pulse_us` at `min_angle` to
+/// `max_pulse_us` at `max_angle`, then shifted by `center_trim_us`.
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct ServoConfig {
+    pub min_pulse_us: f32,
+    pub max_pulse_us: f32,
+    /// PWM period, 20 000 µs for the usual 50 Hz.
+    pub period_us: f32,
+    pub min_angle: f32,
+    pub max_angle: f32,
+    /// Added to every pulse to correct a horn that is not mounted straight.
+    pub center_trim_us: f32,
+    /// How fast the servo itself turns, from its datasheet (0.1 s/60° is 600°/s).
+    pub rated_speed_deg_s: f32,
+    /// Extra wait after the servo should have arrived, for it to stop wobbling.
+    pub settle: Duration,
+    /// How often `try_move_to` updates the commanded angle, one PWM period is plenty.
+    pub step_interval: Duration,
+}
+
+impl Default for ServoConfig {
+    /// Typical SG90/MG90 style servo: 500–2500 µs over 0–180° at 50 Hz.
+    fn default() -> Self {
+        Self {
+            min_pulse_us: 500.0,
+            max_pulse_us: 2500.0,
+            period_us: 20_000.0,
+            min_angle: 0.0,
+            max_angle: 180.0,
+            center_trim_us: 0.0,
+            rated_speed_deg_s: 600.0,
+            settle: Duration::from_millis(20),
+            step_interval: Duration::from_millis(20),
+        }
+    }
+}
+
+impl ServoConfig {
+    /// Angle in the middle of the travel.
+    pub fn center(&self) -> f32 {
+        (self.min_angle + self.max_angle) / 2.0
+    }
+
+    /// Pulse width for `angle`, clamped to the angle range and, after the trim,
+    /// to the pulse range so the servo is never driven past its end stops.
+    pub fn pulse_us(&self, angle: f32) -> f32 {
+        let angle = self.clamp_angle(angle);
+        let span = self.max_angle - self.min_angle;
+        let fraction = if span > 0.0 {
+            (angle - self.min_angle) / span
+        } else {
+            0.5
+        };
+        let pulse = self.min_pulse_us + fraction * (self.max_pulse_us - self.min_pulse_us);
+        (pulse + self.center_trim_us).clamp(self.min_pulse_us, self.max_pulse_us)
+    }
+
+    pub fn clamp_angle(&self, angle: f32) -> f32 {
+        angle.clamp(self.min_angle, self.max_angle)
+    }
+
+    /// Time the servo needs to turn `degrees` at its rated speed.
+    pub fn travel_time(&self, degrees: f32) -> Duration {
+        let seconds = degrees.abs() / self.rated_speed_deg_s;
+        Duration::from_micros((seconds * 1_000_000.0) as u64)
+    }
+
+    /// Duty counts for `angle` on a PWM whose full period is `max_duty` counts.
+    pub fn duty(&self, angle: f32, max_duty: u16) -> u16 {
+        let fraction = (self.pulse_us(angle) / self.period_us).clamp(0.0, 1.0);
+        (fraction * max_duty as f32).round() as u16
+    }
+}
 
 #[derive(Debug, Clone, Copy, defmt::Format)]
 pub struct Servo<T: SetDutyCycle> {
     pwm_out: T,
-    duty_on_zero: u16,
-    duty_per_degree: f32,
-    max_angle: f32,
+    config: ServoConfig,
+    angle: Option<f32>, // last commanded, unknown until the first command
 }
 
 impl<T: SetDutyCycle> Servo<T> {
-    pub fn new(pwm_out: T, period: u8, max_angle: f32, max_duty: u16) -> Self {
-        let duty_on_zero = max_duty / (period * 2) as u16; // servo pulse range
-        let duty_on_90 = duty_on_zero * 3;
-        let duty_per_degree = (duty_on_90 - duty_on_zero) as f32 / 90.0;
+    pub fn new(pwm_out: T, config: ServoConfig) -> Self {
         Self {
             pwm_out,
-            duty_on_zero,
-            duty_per_degree,
-            max_angle,
+            config,
+            angle: None,
         }
     }
 
+    pub fn config(&self) -> ServoConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: ServoConfig) {
+        self.config = config;
+    }
+
+    /// The commanded angle, which the servo may still be on its way to.
+    pub fn angle(&self) -> Option<f32> {
+        self.angle
+    }
+
+    pub fn try_set_angle(&mut self, angle: f32) -> Result<(), Error> {
+        let angle = self.config.clamp_angle(angle);
+        let duty = self.config.duty(angle, self.pwm_out.max_duty_cycle());
+        self.pwm_out.set_duty_cycle(duty).map_err(Error::pwm)?;
+        self.angle = Some(angle);
+        Ok(())
+    }
+
+    /// Sweeps the commanded angle to `angle` at `deg_per_sec` and returns once
+    /// the servo should be there and settled.
+    ///
+    /// A speed that is not positive or finite jumps straight to the target.
+    /// Commanding faster than the rated speed makes the servo lag behind, the
+    /// remaining travel is waited out before the settle time.
+    pub async fn try_move_to(&mut self, angle: f32, deg_per_sec: f32) -> Result<(), Error> {
+        self.try_move_to_with(angle, deg_per_sec, &mut Delay).await
+    }
+
+    /// `try_move_to` with an explicit delay provider.
+    pub async fn try_move_to_with<D: DelayNs>(
+        &mut self,
+        angle: f32,
+        deg_per_sec: f32,
+        delay: &mut D,
+    ) -> Result<(), Error> {
+        let target = self.config.clamp_angle(angle);
+        // from an unknown position assume the longest way
+        let start = self.angle.unwrap_or(if target > self.config.center() {
+            self.config.min_angle
+        } else {
+            self.config.max_angle
+        });
+        let distance = target - start;
+
+        let mut travel_us = 0;
+        if self.angle.is_some() && deg_per_sec.is_finite() && deg_per_sec > 0.0 {
+            let step_us = self.config.step_interval.as_micros().max(1);
+            let sweep_us = (distance.abs() / deg_per_sec * 1_000_000.0) as u64;
+            let steps = sweep_us.div_ceil(step_us);
+            for step in 1..=steps {
+                self.try_set_angle(start + distance * step as f32 / steps as f32)?;
+                delay.delay_us(step_us as u32).await;
+            }
+            travel_us = steps * step_us;
+        }
+        self.try_set_angle(target)?;
+
+        let lag_us = self
+            .config
+            .travel_time(distance)
+            .as_micros()
+            .saturating_sub(travel_us);
+        let wait_us = lag_us + self.config.settle.as_micros();
+        delay.delay_us(wait_us as u32).await;
+        Ok(())
+    }
+
+    pub fn try_center(&mut self) -> Result<(), Error> {
+        self.try_set_angle(self.config.center())
+    }
+}
+
+impl<T: SetDutyCycle<Error = Infallible>> Servo<T> {
     pub fn set_angle(&mut self, angle: f32) {
-        let mut angle = angle;
+        infallible(self.try_set_angle(angle))
+    }
+
+    pub fn center(&mut self) {
+        infallible(self.try_center())
+    }
+
+    pub async fn move_to(&mut self, angle: f32, deg_per_sec: f32) {
+        infallible(self.try_move_to(angle, deg_per_sec).await)
+    }
+}
+
+/// Pulse timing of a continuous rotation servo.
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct ContinuousServoConfig {
+    /// Pulse at which this particular servo stands still, usually close to 1500 µs.
+    pub neutral_pulse_us: f32,
+    /// Distance of the full speed pulse from neutral, either way.
+    pub full_speed_offset_us: f32,
+    /// PWM period, 20 000 µs for the usual 50 Hz.
+    pub period_us: f32,
+}
+
+impl Default for ContinuousServoConfig {
+    /// 1000–2000 µs around a 1500 µs neutral at 50 Hz.
+    fn default() -> Self {
+        Self {
+            neutral_pulse_us: 1500.0,
+            full_speed_offset_us: 500.0,
+            period_us: 20_000.0,
+        }
+    }
+}
+
+impl ContinuousServoConfig {
+    /// Pulse width for a speed in percent, clamped to ±100.
+    pub fn pulse_us(&self, speed: f32) -> f32 {
+        self.neutral_pulse_us + speed.clamp(-100.0, 100.0) / 100.0 * self.full_speed_offset_us
+    }
+
+    pub fn duty(&self, speed: f32, max_duty: u16) -> u16 {
+        let fraction = (self.pulse_us(speed) / self.period_us).clamp(0.0, 1.0);
+        (fraction * max_duty as f32).round() as u16
+    }
+}
+
+/// A servo modified for continuous rotation, driven like a DC motor.
+///
+/// Braking holds the neutral pulse, coasting stops the pulses so the servo goes limp.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct ContinuousServo<T: SetDutyCycle> {
+    pwm_out: T,
+    config: ContinuousServoConfig,
+    speed: f32,
+    inverted: bool,
+}
+
+impl<T: SetDutyCycle> ContinuousServo<T> {
+    pub fn new(pwm_out: T, config: ContinuousServoConfig) -> Self {
+        Self {
+            pwm_out,
+            config,
+            speed: 0.0,
+            inverted: false,
+        }
+    }
 
-        if angle > self.max_angle {
-            angle = self.max_angle
+    /// For the mirrored servo of a chassis: a positive speed still drives the robot forward.
+    pub fn new_invert(pwm_out: T, config: ContinuousServoConfig) -> Self {
+        Self {
+            inverted: true,
+            ..Self::new(pwm_out, config)
         }
+    }
+
+    pub fn config(&self) -> ContinuousServoConfig {
+        self.config
+    }
+
+    /// Trims the stand-still pulse, found by lowering or raising it until the wheel stops creeping.
+    pub fn set_neutral_pulse_us(&mut self, neutral_pulse_us: f32) {
+        self.config.neutral_pulse_us = neutral_pulse_us;
+    }
 
-        let duty_on_the_degree = (self.duty_per_degree * angle) as u16 + self.duty_on_zero;
+    pub fn is_inverted(&self) -> bool {
+        self.inverted
+    }
+
+    pub fn get_speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Speed in percent, -100..100, negative is backward.
+    pub fn try_run(&mut self, speed: f32) -> Result<(), Error> {
+        let speed = speed.clamp(-100.0, 100.0);
+        let wheel_speed = if self.inverted { -speed } else { speed };
+        let duty = self.config.duty(wheel_speed, self.pwm_out.max_duty_cycle());
+        self.pwm_out.set_duty_cycle(duty).map_err(Error::pwm)?;
+        self.speed = speed;
+        Ok(())
+    }
+
+    /// Holds the neutral pulse, the servo actively keeps the wheel still.
+    pub fn try_stop(&mut self) -> Result<(), Error> {
+        self.try_run(0.0)
+    }
+
+    /// Stops sending pulses, the servo no longer drives the wheel.
+    pub fn try_coast(&mut self) -> Result<(), Error> {
+        self.pwm_out
+            .set_duty_cycle_fully_off()
+            .map_err(Error::pwm)?;
+        self.speed = 0.0;
+        Ok(())
+    }
+}
+
+impl<T: SetDutyCycle<Error = Infallible>> ContinuousServo<T> {
+    pub fn run(&mut self, speed: f32) {
+        infallible(self.try_run(speed))
+    }
+
+    pub fn stop(&mut self) {
+        infallible(self.try_stop())
+    }
+
+    pub fn coast(&mut self) {
+        infallible(self.try_coast())
+    }
+}
+
+impl<T: SetDutyCycle> DcMotor for ContinuousServo<T> {
+    fn try_run(&mut self, speed: f32) -> Result<(), Error> {
+        ContinuousServo::try_run(self, speed)
+    }
+
+    /// Brakes, a servo has no stop mode of its own.
+    fn try_stop(&mut self) -> Result<(), Error> {
+        ContinuousServo::try_stop(self)
+    }
+
+    fn try_brake(&mut self) -> Result<(), Error> {
+        ContinuousServo::try_stop(self)
+    }
 
-        self.pwm_out.set_duty_cycle(duty_on_the_degree).unwrap();
+    fn try_coast(&mut self) -> Result<(), Error> {
+        ContinuousServo::try_coast(self)
     }
 }
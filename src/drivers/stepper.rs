@@ -0,0 +1,305 @@
+use embassy_time::Delay;
+use embedded_hal::digital::{OutputPin, PinState};
+use embedded_hal_async::delay::DelayNs;
+use num_traits::float::FloatCore;
+
+use crate::error::Error;
+
+/// Two coils energised at a time, most torque.
+const FULL_STEP: [[bool; 4]; 4] = [
+    [true, true, false, false],
+    [false, true, true, false],
+    [false, false, true, true],
+    [true, false, false, true],
+];
+
+/// Alternates one and two energised coils, twice the resolution.
+const HALF_STEP: [[bool; 4]; 8] = [
+    [true, false, false, false],
+    [true, true, false, false],
+    [false, true, false, false],
+    [false, true, true, false],
+    [false, false, true, false],
+    [false, false, true, true],
+    [false, false, false, true],
+    [true, false, false, true],
+];
+
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub enum StepMode {
+    #[default]
+    Full,
+    Half,
+}
+
+impl StepMode {
+    /// Coil states for a phase of the sequence, any phase wraps around.
+    pub fn coils(&self, phase: i32) -> [bool; 4] {
+        match self {
+            StepMode::Full => FULL_STEP[phase.rem_euclid(4) as usize],
+            StepMode::Half => HALF_STEP[phase.rem_euclid(8) as usize],
+        }
+    }
+}
+
+/// The hardware side of a stepper, moving one step at a time.
+pub trait StepperOutput {
+    /// Sets the direction of the following steps.
+    fn try_set_direction(&mut self, forward: bool) -> Result<(), Error>;
+
+    /// Starts one step in the current direction.
+    fn try_step(&mut self) -> Result<(), Error>;
+
+    /// Ends the step started by `try_step`, half a step interval later.
+    fn try_end_step(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// De-energises the coils, where the hardware allows it.
+    fn try_release(&mut self) -> Result<(), Error>;
+}
+
+/// Unipolar stepper such as the 28BYJ-48 on a ULN2003 board, or a bipolar one
+/// on a dual H-bridge, with the four coil inputs driven directly.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct FourWire<P: OutputPin> {
+    pins: [P; 4],
+    mode: StepMode,
+    phase: i32,
+    forward: bool,
+}
+
+impl<P: OutputPin> FourWire<P> {
+    /// Pins in coil order, IN1 to IN4 on a ULN2003 board.
+    pub fn new(pins: [P; 4], mode: StepMode) -> Self {
+        Self {
+            pins,
+            mode,
+            phase: 0,
+            forward: true,
+        }
+    }
+
+    pub fn mode(&self) -> StepMode {
+        self.mode
+    }
+
+    fn write(&mut self, coils: [bool; 4]) -> Result<(), Error> {
+        // set every pin even if one fails
+        let mut result = Ok(());
+        for (pin, on) in self.pins.iter_mut().zip(coils) {
+            let written = pin.set_state(PinState::from(on)).map_err(Error::pin);
+            result = result.and(written);
+        }
+        result
+    }
+}
+
+impl<P: OutputPin> StepperOutput for FourWire<P> {
+    fn try_set_direction(&mut self, forward: bool) -> Result<(), Error> {
+        self.forward = forward;
+        Ok(())
+    }
+
+    fn try_step(&mut self) -> Result<(), Error> {
+        self.phase += if self.forward { 1 } else { -1 };
+        self.write(self.mode.coils(self.phase))
+    }
+
+    fn try_release(&mut self) -> Result<(), Error> {
+        self.write([false; 4])
+    }
+}
+
+/// STEP/DIR driver such as the A4988 or DRV8825, which steps on the rising edge.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct StepDir<S: OutputPin, D: OutputPin> {
+    step_pin: S,
+    dir_pin: D,
+}
+
+impl<S: OutputPin, D: OutputPin> StepDir<S, D> {
+    pub fn new(step_pin: S, dir_pin: D) -> Self {
+        Self { step_pin, dir_pin }
+    }
+}
+
+impl<S: OutputPin, D: OutputPin> StepperOutput for StepDir<S, D> {
+    fn try_set_direction(&mut self, forward: bool) -> Result<(), Error> {
+        self.dir_pin
+            .set_state(PinState::from(forward))
+            .map_err(Error::pin)
+    }
+
+    fn try_step(&mut self) -> Result<(), Error> {
+        self.step_pin.set_high().map_err(Error::pin)
+    }
+
+    fn try_end_step(&mut self) -> Result<(), Error> {
+        self.step_pin.set_low().map_err(Error::pin)
+    }
+
+    /// The driver keeps holding, wire its ENABLE pin to release the motor.
+    fn try_release(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Trapezoidal speed profile in steps per second.
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct MotionProfile {
+    /// Speed the motor can start and stop at without losing steps.
+    pub start_speed: f32,
+    pub max_speed: f32,
+    /// Steps per second squared, not positive or infinite moves at `max_speed` throughout.
+    pub acceleration: f32,
+}
+
+impl Default for MotionProfile {
+    /// Gentle enough for a 28BYJ-48 in half-step mode.
+    fn default() -> Self {
+        Self {
+            start_speed: 100.0,
+            max_speed: 500.0,
+            acceleration: 1000.0,
+        }
+    }
+}
+
+impl MotionProfile {
+    /// Time between step `index` and the next one of a move of `total` steps,
+    /// accelerating from the start and braking towards the end.
+    pub fn step_interval_us(&self, index: u32, total: u32) -> u32 {
+        let speed = if self.acceleration > 0.0 && self.acceleration.is_finite() {
+            let steps_left = total.saturating_sub(index + 1);
+            let ramp = index.min(steps_left) as f32;
+            let start = self.start_speed * self.start_speed;
+            libm::sqrtf(start + 2.0 * self.acceleration * ramp).min(self.max_speed)
+        } else {
+            self.max_speed
+        };
+        (1_000_000.0 / speed.max(1.0)) as u32
+    }
+}
+
+/// A stepper that keeps track of its absolute position in steps.
+///
+/// The position is only as good as the assumption that no step was lost;
+/// `set_position` re-zeroes it, e.g. against an end stop.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct Stepper<O: StepperOutput> {
+    output: O,
+    steps_per_rev: u32,
+    profile: MotionProfile,
+    position: i32,
+}
+
+impl<O: StepperOutput> Stepper<O> {
+    /// `steps_per_rev` counts the steps of the output's sequence, 4096 for a
+    /// half-stepped 28BYJ-48, 200 times the microstepping for a NEMA 17.
+    pub fn new(output: O, steps_per_rev: u32) -> Self {
+        Self {
+            output,
+            steps_per_rev: steps_per_rev.max(1),
+            profile: MotionProfile::default(),
+            position: 0,
+        }
+    }
+
+    pub fn with_profile(mut self, profile: MotionProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn profile(&self) -> MotionProfile {
+        self.profile
+    }
+
+    pub fn output_mut(&mut self) -> &mut O {
+        &mut self.output
+    }
+
+    pub fn position(&self) -> i32 {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: i32) {
+        self.position = position;
+    }
+
+    /// Position as an angle within one revolution, 0..360.
+    pub fn angle(&self) -> f32 {
+        let steps = self.position.rem_euclid(self.steps_per_rev as i32);
+        steps as f32 * 360.0 / self.steps_per_rev as f32
+    }
+
+    pub fn try_release(&mut self) -> Result<(), Error> {
+        self.output.try_release()
+    }
+
+    /// Moves `steps` relative to the current position, negative is backward.
+    pub async fn try_move_steps(&mut self, steps: i32) -> Result<(), Error> {
+        self.try_move_steps_with(steps, &mut Delay).await
+    }
+
+    /// `try_move_steps` with an explicit delay provider.
+    pub async fn try_move_steps_with<D: DelayNs>(
+        &mut self,
+        steps: i32,
+        delay: &mut D,
+    ) -> Result<(), Error> {
+        let forward = steps >= 0;
+        let direction = if forward { 1 } else { -1 };
+        let total = steps.unsigned_abs();
+        self.output.try_set_direction(forward)?;
+        // STEP/DIR drivers want DIR settled before the step edge, 650 ns for the DRV8825
+        delay.delay_us(1).await;
+
+        for index in 0..total {
+            let interval_us = self.profile.step_interval_us(index, total);
+            self.output.try_step()?;
+            self.position += direction;
+            delay.delay_us(interval_us / 2).await;
+            self.output.try_end_step()?;
+            delay.delay_us(interval_us - interval_us / 2).await;
+        }
+        Ok(())
+    }
+
+    /// Moves to an absolute position in steps. A target more than `i32::MAX`
+    /// steps away is only approached by `i32::MAX` steps.
+    pub async fn try_move_to(&mut self, position: i32) -> Result<(), Error> {
+        self.try_move_to_with(position, &mut Delay).await
+    }
+
+    /// `try_move_to` with an explicit delay provider.
+    pub async fn try_move_to_with<D: DelayNs>(
+        &mut self,
+        position: i32,
+        delay: &mut D,
+    ) -> Result<(), Error> {
+        self.try_move_steps_with(position.saturating_sub(self.position), delay)
+            .await
+    }
+
+    /// Turns the shortest way to an angle within the revolution, 0° being position 0.
+    pub async fn try_move_to_angle(&mut self, angle: f32) -> Result<(), Error> {
+        self.try_move_to_angle_with(angle, &mut Delay).await
+    }
+
+    /// `try_move_to_angle` with an explicit delay provider.
+    pub async fn try_move_to_angle_with<D: DelayNs>(
+        &mut self,
+        angle: f32,
+        delay: &mut D,
+    ) -> Result<(), Error> {
+        let steps_per_rev = self.steps_per_rev as i32;
+        let target = (angle / 360.0 * steps_per_rev as f32).round() as i32;
+        let current = self.position.rem_euclid(steps_per_rev);
+        let mut steps = (target - current).rem_euclid(steps_per_rev);
+        if steps > steps_per_rev / 2 {
+            steps -= steps_per_rev;
+        }
+        self.try_move_steps_with(steps, delay).await
+    }
+}
@@ -0,0 +1,78 @@
+pub mod vl53l0x;
+pub mod vl53l1x;
+
+pub use vl53l0x::{Vl53l0x, Vl53l0xConfig};
+pub use vl53l1x::{DistanceMode, TimingBudget, Vl53l1x, Vl53l1xConfig};
+
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::delay::DelayNs;
+
+use crate::error::Error;
+
+/// Both chips answer at this address after power up.
+pub const DEFAULT_ADDRESS: u8 = 0x29;
+
+/// Polls for a result or a calibration step give up after this many milliseconds.
+const POLL_TIMEOUT_MS: u32 = 500;
+
+/// A sensor that can be moved to another I2C address.
+#[allow(async_fn_in_trait)]
+pub trait Addressable {
+    fn address(&self) -> u8;
+
+    /// Moves the chip from the address it answers at now to `address`,
+    /// `Error::InvalidAddress` outside 0x08..=0x77.
+    async fn try_set_address(&mut self, address: u8) -> Result<(), Error>;
+}
+
+/// Gives every sensor on one bus its own address, `first_address` and up.
+///
+/// All the chips wake up at `DEFAULT_ADDRESS`. Every XSHUT is pulled low,
+/// then the chips are woken one at a time and moved away before the next
+/// one wakes up. The sensors must be freshly created, still at the default
+/// address, and `xshut[i]` must belong to `sensors[i]`. Initialise them afterwards.
+pub async fn assign_addresses<S: Addressable, P: OutputPin, D: DelayNs>(
+    sensors: &mut [S],
+    xshut: &mut [P],
+    first_address: u8,
+    delay: &mut D,
+) -> Result<(), Error> {
+    debug_assert_eq!(sensors.len(), xshut.len());
+    // check the whole range before any chip is moved
+    let last_address = first_address as usize + sensors.len().saturating_sub(1);
+    let last_address = u8::try_from(last_address).unwrap_or(u8::MAX);
+    check_address(first_address)?;
+    check_address(last_address)?;
+
+    for pin in xshut.iter_mut() {
+        pin.set_low().map_err(Error::pin)?;
+    }
+    delay.delay_ms(10).await;
+
+    let addresses = first_address..=last_address;
+    for (address, (sensor, pin)) in addresses.zip(sensors.iter_mut().zip(xshut)) {
+        pin.set_high().map_err(Error::pin)?;
+        // boot time of both chips
+        delay.delay_ms(2).await;
+        sensor.try_set_address(address).await?;
+    }
+    Ok(())
+}
+
+/// `address` if it is free for devices, the rest are reserved by the I2C specification.
+fn check_address(address: u8) -> Result<u8, Error> {
+    if (0x08..=0x77).contains(&address) {
+        Ok(address)
+    } else {
+        Err(Error::InvalidAddress(address))
+    }
+}
+
+/// Share of the returned light that came from the target and not the ambient light.
+fn signal_quality(signal: f32, ambient: f32) -> f32 {
+    if signal + ambient > 0.0 {
+        signal / (signal + ambient)
+    } else {
+        0.0
+    }
+}
@@ -0,0 +1,612 @@
+use embassy_time::{Delay, Duration};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::i2c::I2c;
+
+use crate::drivers::range::{Clock, Range, RangeError, RangeSensor, SystemClock};
+use crate::drivers::tof::{
+    Addressable, DEFAULT_ADDRESS, POLL_TIMEOUT_MS, check_address, signal_quality,
+};
+use crate::error::Error;
+
+mod reg {
+    pub const SYSRANGE_START: u8 = 0x00;
+    pub const SYSTEM_SEQUENCE_CONFIG: u8 = 0x01;
+    pub const SYSTEM_INTERMEASUREMENT_PERIOD: u8 = 0x04;
+    pub const SYSTEM_INTERRUPT_CONFIG_GPIO: u8 = 0x0A;
+    pub const SYSTEM_INTERRUPT_CLEAR: u8 = 0x0B;
+    pub const RESULT_INTERRUPT_STATUS: u8 = 0x13;
+    pub const RESULT_RANGE_STATUS: u8 = 0x14;
+    pub const FINAL_RANGE_CONFIG_MIN_COUNT_RATE_RTN_LIMIT: u8 = 0x44;
+    pub const MSRC_CONFIG_TIMEOUT_MACROP: u8 = 0x46;
+    pub const DYNAMIC_SPAD_NUM_REQUESTED_REF_SPAD: u8 = 0x4E;
+    pub const DYNAMIC_SPAD_REF_EN_START_OFFSET: u8 = 0x4F;
+    pub const PRE_RANGE_CONFIG_VCSEL_PERIOD: u8 = 0x50;
+    pub const PRE_RANGE_CONFIG_TIMEOUT_MACROP_HI: u8 = 0x51;
+    pub const MSRC_CONFIG_CONTROL: u8 = 0x60;
+    pub const FINAL_RANGE_CONFIG_VCSEL_PERIOD: u8 = 0x70;
+    pub const FINAL_RANGE_CONFIG_TIMEOUT_MACROP_HI: u8 = 0x71;
+    pub const GPIO_HV_MUX_ACTIVE_HIGH: u8 = 0x84;
+    pub const VHV_CONFIG_PAD_SCL_SDA_EXTSUP_HV: u8 = 0x89;
+    pub const I2C_SLAVE_DEVICE_ADDRESS: u8 = 0x8A;
+    pub const GLOBAL_CONFIG_SPAD_ENABLES_REF_0: u8 = 0xB0;
+    pub const GLOBAL_CONFIG_REF_EN_START_SELECT: u8 = 0xB6;
+    pub const IDENTIFICATION_MODEL_ID: u8 = 0xC0;
+    pub const OSC_CALIBRATE_VAL: u8 = 0xF8;
+}
+
+const MODEL_ID: u8 = 0xEE;
+
+/// Range status of a valid measurement.
+const RANGE_COMPLETE: u8 = 11;
+/// Reported as the distance when nothing was in range.
+const NO_TARGET_MM: u16 = 8190;
+
+/// Register writes from ST's API that tune the chip after reset, `0xFF` switches pages.
+const TUNING: [(u8, u8); 80] = [
+    (0xFF, 0x01),
+    (0x00, 0x00),
+    (0xFF, 0x00),
+    (0x09, 0x00),
+    (0x10, 0x00),
+    (0x11, 0x00),
+    (0x24, 0x01),
+    (0x25, 0xFF),
+    (0x75, 0x00),
+    (0xFF, 0x01),
+    (0x4E, 0x2C),
+    (0x48, 0x00),
+    (0x30, 0x20),
+    (0xFF, 0x00),
+    (0x30, 0x09),
+    (0x54, 0x00),
+    (0x31, 0x04),
+    (0x32, 0x03),
+    (0x40, 0x83),
+    (0x46, 0x25),
+    (0x60, 0x00),
+    (0x27, 0x00),
+    (0x50, 0x06),
+    (0x51, 0x00),
+    (0x52, 0x96),
+    (0x56, 0x08),
+    (0x57, 0x30),
+    (0x61, 0x00),
+    (0x62, 0x00),
+    (0x64, 0x00),
+    (0x65, 0x00),
+    (0x66, 0xA0),
+    (0xFF, 0x01),
+    (0x22, 0x32),
+    (0x47, 0x14),
+    (0x49, 0xFF),
+    (0x4A, 0x00),
+    (0xFF, 0x00),
+    (0x7A, 0x0A),
+    (0x7B, 0x00),
+    (0x78, 0x21),
+    (0xFF, 0x01),
+    (0x23, 0x34),
+    (0x42, 0x00),
+    (0x44, 0xFF),
+    (0x45, 0x26),
+    (0x46, 0x05),
+    (0x40, 0x40),
+    (0x0E, 0x06),
+    (0x20, 0x1A),
+    (0x43, 0x40),
+    (0xFF, 0x00),
+    (0x34, 0x03),
+    (0x35, 0x44),
+    (0xFF, 0x01),
+    (0x31, 0x04),
+    (0x4B, 0x09),
+    (0x4C, 0x05),
+    (0x4D, 0x04),
+    (0xFF, 0x00),
+    (0x44, 0x00),
+    (0x45, 0x20),
+    (0x47, 0x08),
+    (0x48, 0x28),
+    (0x67, 0x00),
+    (0x70, 0x04),
+    (0x71, 0x01),
+    (0x72, 0xFE),
+    (0x76, 0x00),
+    (0x77, 0x00),
+    (0xFF, 0x01),
+    (0x0D, 0x01),
+    (0xFF, 0x00),
+    (0x80, 0x01),
+    (0x01, 0xF8),
+    (0xFF, 0x01),
+    (0x8E, 0x01),
+    (0x00, 0x01),
+    (0xFF, 0x00),
+    (0x80, 0x00),
+];
+
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct Vl53l0xConfig {
+    /// Time for one measurement, at least 20 ms. Longer is more precise and reaches farther.
+    pub timing_budget_us: u32,
+    /// Weakest return signal counted as a target, lower reaches farther but misreads more.
+    pub signal_rate_limit_mcps: f32,
+    /// Board with its I/O at 2.8 V, like most breakout boards, instead of 1.8 V.
+    pub io_2v8: bool,
+}
+
+impl Default for Vl53l0xConfig {
+    /// ST's defaults, about 30 measurements per second up to 1.2 m.
+    fn default() -> Self {
+        Self {
+            timing_budget_us: 33_000,
+            signal_rate_limit_mcps: 0.25,
+            io_2v8: true,
+        }
+    }
+}
+
+/// ST VL53L0X time-of-flight distance sensor on an async I2C bus, up to about 2 m.
+///
+/// A port of the initialisation of ST's API, as trimmed down by Pololu.
+/// `try_measure` returns the results of continuous ranging, starting it
+/// back-to-back if `try_start_continuous` was not called.
+#[derive(Debug, Clone, defmt::Format)]
+pub struct Vl53l0x<I: I2c, D: DelayNs = Delay, C: Clock = SystemClock> {
+    i2c: I,
+    delay: D,
+    clock: C,
+    address: u8,
+    config: Vl53l0xConfig,
+    stop_variable: u8,
+    ranging: bool,
+}
+
+impl<I: I2c> Vl53l0x<I> {
+    pub fn new(i2c: I) -> Self {
+        Self {
+            i2c,
+            delay: Delay,
+            clock: SystemClock,
+            address: DEFAULT_ADDRESS,
+            config: Vl53l0xConfig::default(),
+            stop_variable: 0,
+            ranging: false,
+        }
+    }
+}
+
+/// Sequence steps enabled in `SYSTEM_SEQUENCE_CONFIG`.
+struct SequenceSteps {
+    tcc: bool,
+    dss: bool,
+    msrc: bool,
+    pre_range: bool,
+    final_range: bool,
+}
+
+/// Durations of the sequence steps, in µs unless noted.
+struct SequenceTimeouts {
+    msrc_dss_tcc_us: u32,
+    pre_range_mclks: u32,
+    pre_range_us: u32,
+    final_range_vcsel_pclks: u32,
+}
+
+impl<I: I2c, D: DelayNs, C: Clock> Vl53l0x<I, D, C> {
+    /// The delay used to poll the chip.
+    pub fn with_delay<E: DelayNs>(self, delay: E) -> Vl53l0x<I, E, C> {
+        Vl53l0x {
+            i2c: self.i2c,
+            delay,
+            clock: self.clock,
+            address: self.address,
+            config: self.config,
+            stop_variable: self.stop_variable,
+            ranging: self.ranging,
+        }
+    }
+
+    /// The clock that timestamps the ranges.
+    pub fn with_clock<K: Clock>(self, clock: K) -> Vl53l0x<I, D, K> {
+        Vl53l0x {
+            i2c: self.i2c,
+            delay: self.delay,
+            clock,
+            address: self.address,
+            config: self.config,
+            stop_variable: self.stop_variable,
+            ranging: self.ranging,
+        }
+    }
+
+    pub fn with_config(mut self, config: Vl53l0xConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn config(&self) -> Vl53l0xConfig {
+        self.config
+    }
+
+    /// Checks the chip id, tunes the chip, sets the timing budget and calibrates it.
+    pub async fn try_init(&mut self) -> Result<(), Error> {
+        let id = self.read_u8(reg::IDENTIFICATION_MODEL_ID).await?;
+        if id != MODEL_ID {
+            return Err(Error::UnknownDevice(id));
+        }
+
+        if self.config.io_2v8 {
+            let pad = self.read_u8(reg::VHV_CONFIG_PAD_SCL_SDA_EXTSUP_HV).await?;
+            self.write_u8(reg::VHV_CONFIG_PAD_SCL_SDA_EXTSUP_HV, pad | 0x01)
+                .await?;
+        }
+        // standard I2C mode
+        self.write_u8(0x88, 0x00).await?;
+        self.write_u8(0x80, 0x01).await?;
+        self.write_u8(0xFF, 0x01).await?;
+        self.write_u8(0x00, 0x00).await?;
+        self.stop_variable = self.read_u8(0x91).await?;
+        self.write_u8(0x00, 0x01).await?;
+        self.write_u8(0xFF, 0x00).await?;
+        self.write_u8(0x80, 0x00).await?;
+
+        // no signal rate or minimum count rate checks in the pre-range
+        let msrc = self.read_u8(reg::MSRC_CONFIG_CONTROL).await?;
+        self.write_u8(reg::MSRC_CONFIG_CONTROL, msrc | 0x12).await?;
+        let limit = (self.config.signal_rate_limit_mcps * 128.0) as u16; // 9.7 fixed point
+        self.write_u16(reg::FINAL_RANGE_CONFIG_MIN_COUNT_RATE_RTN_LIMIT, limit)
+            .await?;
+        self.write_u8(reg::SYSTEM_SEQUENCE_CONFIG, 0xFF).await?;
+
+        self.set_reference_spads().await?;
+        for (register, value) in TUNING {
+            self.write_u8(register, value).await?;
+        }
+
+        // interrupt on a new sample, active low
+        self.write_u8(reg::SYSTEM_INTERRUPT_CONFIG_GPIO, 0x04)
+            .await?;
+        let mux = self.read_u8(reg::GPIO_HV_MUX_ACTIVE_HIGH).await?;
+        self.write_u8(reg::GPIO_HV_MUX_ACTIVE_HIGH, mux & !0x10)
+            .await?;
+        self.write_u8(reg::SYSTEM_INTERRUPT_CLEAR, 0x01).await?;
+
+        // skip the MSRC and TCC steps
+        self.write_u8(reg::SYSTEM_SEQUENCE_CONFIG, 0xE8).await?;
+        self.try_set_timing_budget(self.config.timing_budget_us)
+            .await?;
+
+        // VHV then phase calibration
+        self.write_u8(reg::SYSTEM_SEQUENCE_CONFIG, 0x01).await?;
+        self.single_ref_calibration(0x40).await?;
+        self.write_u8(reg::SYSTEM_SEQUENCE_CONFIG, 0x02).await?;
+        self.single_ref_calibration(0x00).await?;
+        self.write_u8(reg::SYSTEM_SEQUENCE_CONFIG, 0xE8).await
+    }
+
+    /// Sets the time one measurement takes, stretching the final range step. At least 20 ms.
+    pub async fn try_set_timing_budget(&mut self, budget_us: u32) -> Result<(), Error> {
+        const START_OVERHEAD: u32 = 1320;
+        const END_OVERHEAD: u32 = 960;
+        const MSRC_OVERHEAD: u32 = 660;
+        const TCC_OVERHEAD: u32 = 590;
+        const DSS_OVERHEAD: u32 = 690;
+        const PRE_RANGE_OVERHEAD: u32 = 660;
+        const FINAL_RANGE_OVERHEAD: u32 = 550;
+
+        let budget_us = budget_us.max(20_000);
+        let steps = self.sequence_steps().await?;
+        let timeouts = self.sequence_timeouts(&steps).await?;
+
+        let mut used_us = START_OVERHEAD + END_OVERHEAD;
+        if steps.tcc {
+            used_us += timeouts.msrc_dss_tcc_us + TCC_OVERHEAD;
+        }
+        if steps.dss {
+            used_us += 2 * (timeouts.msrc_dss_tcc_us + DSS_OVERHEAD);
+        } else if steps.msrc {
+            used_us += timeouts.msrc_dss_tcc_us + MSRC_OVERHEAD;
+        }
+        if steps.pre_range {
+            used_us += timeouts.pre_range_us + PRE_RANGE_OVERHEAD;
+        }
+
+        if steps.final_range {
+            used_us += FINAL_RANGE_OVERHEAD;
+            let final_range_us = budget_us.saturating_sub(used_us);
+            // the final range timeout includes the pre-range
+            let mut final_range_mclks =
+                us_to_mclks(final_range_us, timeouts.final_range_vcsel_pclks);
+            if steps.pre_range {
+                final_range_mclks += timeouts.pre_range_mclks;
+            }
+            self.write_u16(
+                reg::FINAL_RANGE_CONFIG_TIMEOUT_MACROP_HI,
+                encode_timeout(final_range_mclks),
+            )
+            .await?;
+        }
+        self.config.timing_budget_us = budget_us;
+        Ok(())
+    }
+
+    /// Starts ranging over and over, every `period` or back-to-back for a zero period.
+    ///
+    /// A period shorter than the timing budget runs back-to-back as well.
+    pub async fn try_start_continuous(&mut self, period: Duration) -> Result<(), Error> {
+        self.write_u8(0x80, 0x01).await?;
+        self.write_u8(0xFF, 0x01).await?;
+        self.write_u8(0x00, 0x00).await?;
+        self.write_u8(0x91, self.stop_variable).await?;
+        self.write_u8(0x00, 0x01).await?;
+        self.write_u8(0xFF, 0x00).await?;
+        self.write_u8(0x80, 0x00).await?;
+
+        let period_ms = period.as_millis() as u32;
+        if period_ms == 0 {
+            self.write_u8(reg::SYSRANGE_START, 0x02).await?;
+        } else {
+            // the period is counted in oscillator ticks
+            let osc = self.read_u16(reg::OSC_CALIBRATE_VAL).await? as u32;
+            let period = if osc != 0 { period_ms * osc } else { period_ms };
+            self.write_u32(reg::SYSTEM_INTERMEASUREMENT_PERIOD, period)
+                .await?;
+            self.write_u8(reg::SYSRANGE_START, 0x04).await?;
+        }
+        self.ranging = true;
+        Ok(())
+    }
+
+    pub async fn try_stop_continuous(&mut self) -> Result<(), Error> {
+        self.write_u8(reg::SYSRANGE_START, 0x01).await?;
+        self.write_u8(0xFF, 0x01).await?;
+        self.write_u8(0x00, 0x00).await?;
+        self.write_u8(0x91, 0x00).await?;
+        self.write_u8(0x00, 0x01).await?;
+        self.write_u8(0xFF, 0x00).await?;
+        self.ranging = false;
+        Ok(())
+    }
+
+    /// Reads the SPAD count and type from the chip's NVM and enables that many reference SPADs.
+    async fn set_reference_spads(&mut self) -> Result<(), Error> {
+        self.write_u8(0x80, 0x01).await?;
+        self.write_u8(0xFF, 0x01).await?;
+        self.write_u8(0x00, 0x00).await?;
+        self.write_u8(0xFF, 0x06).await?;
+        let value = self.read_u8(0x83).await?;
+        self.write_u8(0x83, value | 0x04).await?;
+        self.write_u8(0xFF, 0x07).await?;
+        self.write_u8(0x81, 0x01).await?;
+        self.write_u8(0x80, 0x01).await?;
+        self.write_u8(0x94, 0x6B).await?;
+        self.write_u8(0x83, 0x00).await?;
+        self.wait_for(0x83, 0xFF).await?;
+        self.write_u8(0x83, 0x01).await?;
+        let info = self.read_u8(0x92).await?;
+        self.write_u8(0x81, 0x00).await?;
+        self.write_u8(0xFF, 0x06).await?;
+        let value = self.read_u8(0x83).await?;
+        self.write_u8(0x83, value & !0x04).await?;
+        self.write_u8(0xFF, 0x01).await?;
+        self.write_u8(0x00, 0x01).await?;
+        self.write_u8(0xFF, 0x00).await?;
+        self.write_u8(0x80, 0x00).await?;
+
+        let count = info & 0x7F;
+        // aperture SPADs start at the 12th
+        let first = if info & 0x80 != 0 { 12 } else { 0 };
+
+        let mut map = [0; 6];
+        self.read_into(reg::GLOBAL_CONFIG_SPAD_ENABLES_REF_0, &mut map)
+            .await?;
+        self.write_u8(0xFF, 0x01).await?;
+        self.write_u8(reg::DYNAMIC_SPAD_REF_EN_START_OFFSET, 0x00)
+            .await?;
+        self.write_u8(reg::DYNAMIC_SPAD_NUM_REQUESTED_REF_SPAD, 0x2C)
+            .await?;
+        self.write_u8(0xFF, 0x00).await?;
+        self.write_u8(reg::GLOBAL_CONFIG_REF_EN_START_SELECT, 0xB4)
+            .await?;
+
+        let mut enabled = 0;
+        for spad in 0..48 {
+            let (byte, bit) = (spad / 8, spad % 8);
+            if spad < first || enabled == count {
+                map[byte] &= !(1 << bit);
+            } else if map[byte] & (1 << bit) != 0 {
+                enabled += 1;
+            }
+        }
+        let mut buf = [0; 7];
+        buf[0] = reg::GLOBAL_CONFIG_SPAD_ENABLES_REF_0;
+        buf[1..].copy_from_slice(&map);
+        self.write(&buf).await
+    }
+
+    async fn single_ref_calibration(&mut self, vhv_init: u8) -> Result<(), Error> {
+        self.write_u8(reg::SYSRANGE_START, 0x01 | vhv_init).await?;
+        self.wait_for(reg::RESULT_INTERRUPT_STATUS, 0x07).await?;
+        self.write_u8(reg::SYSTEM_INTERRUPT_CLEAR, 0x01).await?;
+        self.write_u8(reg::SYSRANGE_START, 0x00).await
+    }
+
+    async fn sequence_steps(&mut self) -> Result<SequenceSteps, Error> {
+        let config = self.read_u8(reg::SYSTEM_SEQUENCE_CONFIG).await?;
+        Ok(SequenceSteps {
+            tcc: config & 0x10 != 0,
+            dss: config & 0x08 != 0,
+            msrc: config & 0x04 != 0,
+            pre_range: config & 0x40 != 0,
+            final_range: config & 0x80 != 0,
+        })
+    }
+
+    async fn sequence_timeouts(
+        &mut self,
+        steps: &SequenceSteps,
+    ) -> Result<SequenceTimeouts, Error> {
+        let pre_range_vcsel_pclks =
+            decode_vcsel_period(self.read_u8(reg::PRE_RANGE_CONFIG_VCSEL_PERIOD).await?);
+        let msrc_dss_tcc_mclks = self.read_u8(reg::MSRC_CONFIG_TIMEOUT_MACROP).await? as u32 + 1;
+        let pre_range_mclks = decode_timeout(
+            self.read_u16(reg::PRE_RANGE_CONFIG_TIMEOUT_MACROP_HI)
+                .await?,
+        );
+        let final_range_vcsel_pclks =
+            decode_vcsel_period(self.read_u8(reg::FINAL_RANGE_CONFIG_VCSEL_PERIOD).await?);
+        Ok(SequenceTimeouts {
+            msrc_dss_tcc_us: mclks_to_us(msrc_dss_tcc_mclks, pre_range_vcsel_pclks),
+            pre_range_mclks: if steps.pre_range { pre_range_mclks } else { 0 },
+            pre_range_us: mclks_to_us(pre_range_mclks, pre_range_vcsel_pclks),
+            final_range_vcsel_pclks,
+        })
+    }
+
+    /// Polls `register` every millisecond until a bit of `mask` is set, or `Error::Timeout`.
+    async fn wait_for(&mut self, register: u8, mask: u8) -> Result<(), Error> {
+        for _ in 0..POLL_TIMEOUT_MS {
+            if self.read_u8(register).await? & mask != 0 {
+                return Ok(());
+            }
+            self.delay.delay_ms(1).await;
+        }
+        Err(Error::Timeout)
+    }
+
+    async fn read_into(&mut self, register: u8, buf: &mut [u8]) -> Result<(), Error> {
+        self.i2c
+            .write_read(self.address, &[register], buf)
+            .await
+            .map_err(Error::i2c)
+    }
+
+    async fn read_u8(&mut self, register: u8) -> Result<u8, Error> {
+        let mut buf = [0];
+        self.read_into(register, &mut buf).await?;
+        Ok(buf[0])
+    }
+
+    async fn read_u16(&mut self, register: u8) -> Result<u16, Error> {
+        let mut buf = [0; 2];
+        self.read_into(register, &mut buf).await?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.i2c
+            .write(self.address, bytes)
+            .await
+            .map_err(Error::i2c)
+    }
+
+    async fn write_u8(&mut self, register: u8, value: u8) -> Result<(), Error> {
+        self.write(&[register, value]).await
+    }
+
+    async fn write_u16(&mut self, register: u8, value: u16) -> Result<(), Error> {
+        let [hi, lo] = value.to_be_bytes();
+        self.write(&[register, hi, lo]).await
+    }
+
+    async fn write_u32(&mut self, register: u8, value: u32) -> Result<(), Error> {
+        let [b0, b1, b2, b3] = value.to_be_bytes();
+        self.write(&[register, b0, b1, b2, b3]).await
+    }
+}
+
+impl<I: I2c, D: DelayNs, C: Clock> Addressable for Vl53l0x<I, D, C> {
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    async fn try_set_address(&mut self, address: u8) -> Result<(), Error> {
+        let address = check_address(address)?;
+        self.write_u8(reg::I2C_SLAVE_DEVICE_ADDRESS, address)
+            .await?;
+        self.address = address;
+        Ok(())
+    }
+}
+
+impl<I: I2c, D: DelayNs, C: Clock> RangeSensor for Vl53l0x<I, D, C> {
+    async fn try_measure(&mut self) -> Result<Range, RangeError> {
+        if !self.ranging {
+            self.try_start_continuous(Duration::from_millis(0)).await?;
+        }
+        self.wait_for(reg::RESULT_INTERRUPT_STATUS, 0x07).await?;
+
+        // status, SPAD count, signal rate, ambient rate and distance in one go
+        let mut result = [0; 12];
+        self.read_into(reg::RESULT_RANGE_STATUS, &mut result)
+            .await?;
+        self.write_u8(reg::SYSTEM_INTERRUPT_CLEAR, 0x01).await?;
+        let timestamp = self.clock.now();
+
+        let status = (result[0] & 0x78) >> 3;
+        let distance_mm = u16::from_be_bytes([result[10], result[11]]);
+        if status != RANGE_COMPLETE || distance_mm >= NO_TARGET_MM {
+            return Err(range_error(status));
+        }
+        // both rates in MCPS as 9.7 fixed point
+        let signal = u16::from_be_bytes([result[6], result[7]]) as f32;
+        let ambient = u16::from_be_bytes([result[8], result[9]]) as f32;
+        Ok(Range {
+            distance_mm: distance_mm as f32,
+            timestamp,
+            quality: signal_quality(signal, ambient),
+        })
+    }
+}
+
+/// Why a measurement with the given range status has no distance, grouped as in ST's API.
+fn range_error(status: u8) -> RangeError {
+    match status {
+        // no target, too little signal or too much noise
+        4 | 5 | 7 | 11 | 14 => RangeError::NoEcho,
+        // minimum range and target centre checks
+        8 | 10 | 12 => RangeError::TooClose,
+        // phase checks, the target is beyond the unambiguous range
+        6 | 9 | 13 => RangeError::TooFar,
+        // VCSEL continuity or watchdog, no VHV value found, or no status at all
+        status => RangeError::Driver(Error::DeviceFault(status)),
+    }
+}
+
+/// Pulse period in PCLKs from its register value.
+fn decode_vcsel_period(value: u8) -> u32 {
+    (value as u32 + 1) << 1
+}
+
+/// Macro period in ns for a VCSEL pulse period.
+fn macro_period_ns(vcsel_pclks: u32) -> u32 {
+    (2304 * vcsel_pclks * 1655 + 500) / 1000
+}
+
+fn mclks_to_us(mclks: u32, vcsel_pclks: u32) -> u32 {
+    (mclks * macro_period_ns(vcsel_pclks) + 500) / 1000
+}
+
+fn us_to_mclks(us: u32, vcsel_pclks: u32) -> u32 {
+    let macro_ns = macro_period_ns(vcsel_pclks);
+    (us * 1000 + macro_ns / 2) / macro_ns
+}
+
+/// Timeouts are stored as `(lsb << msb) + 1` MCLKs.
+fn decode_timeout(value: u16) -> u32 {
+    (((value & 0xFF) as u32) << (value >> 8)) + 1
+}
+
+fn encode_timeout(mclks: u32) -> u16 {
+    if mclks == 0 {
+        return 0;
+    }
+    let mut lsb = mclks - 1;
+    let mut msb = 0;
+    while lsb > 0xFF {
+        lsb >>= 1;
+        msb += 1;
+    }
+    (msb << 8) | lsb as u16
+}
@@ -0,0 +1,372 @@
+use embassy_time::{Delay, Duration, Instant};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::i2c::I2c;
+
+use crate::drivers::range::{Clock, Range, RangeError, RangeSensor, SystemClock};
+use crate::drivers::tof::{
+    Addressable, DEFAULT_ADDRESS, POLL_TIMEOUT_MS, check_address, signal_quality,
+};
+use crate::error::Error;
+
+mod reg {
+    pub const I2C_SLAVE_DEVICE_ADDRESS: u16 = 0x0001;
+    pub const VHV_CONFIG_TIMEOUT_MACROP_LOOP_BOUND: u16 = 0x0008;
+    pub const VHV_CONFIG_INIT: u16 = 0x000B;
+    pub const GPIO_TIO_HV_STATUS: u16 = 0x0031;
+    pub const PHASECAL_CONFIG_TIMEOUT_MACROP: u16 = 0x004B;
+    pub const RANGE_CONFIG_TIMEOUT_MACROP_A_HI: u16 = 0x005E;
+    pub const RANGE_CONFIG_VCSEL_PERIOD_A: u16 = 0x0060;
+    pub const RANGE_CONFIG_TIMEOUT_MACROP_B_HI: u16 = 0x0061;
+    pub const RANGE_CONFIG_VCSEL_PERIOD_B: u16 = 0x0063;
+    pub const RANGE_CONFIG_VALID_PHASE_HIGH: u16 = 0x0069;
+    pub const SYSTEM_INTERMEASUREMENT_PERIOD: u16 = 0x006C;
+    pub const SD_CONFIG_WOI_SD0: u16 = 0x0078;
+    pub const SD_CONFIG_INITIAL_PHASE_SD0: u16 = 0x007A;
+    pub const SYSTEM_INTERRUPT_CLEAR: u16 = 0x0086;
+    pub const SYSTEM_MODE_START: u16 = 0x0087;
+    pub const RESULT_RANGE_STATUS: u16 = 0x0089;
+    pub const RESULT_OSC_CALIBRATE_VAL: u16 = 0x00DE;
+    pub const FIRMWARE_SYSTEM_STATUS: u16 = 0x00E5;
+    pub const IDENTIFICATION_MODEL_ID: u16 = 0x010F;
+}
+
+const MODEL_ID: u8 = 0xEA;
+
+/// First register of `DEFAULT_CONFIGURATION`.
+const CONFIGURATION_START: u16 = 0x002D;
+
+/// Registers 0x2D to 0x87 as set by ST's ultra lite driver: active high
+/// interrupt on new samples, long distance mode, ranging stopped.
+const DEFAULT_CONFIGURATION: [u8; 91] = [
+    0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x02, 0x08, 0x00, 0x08, 0x10, 0x01, 0x01, 0x00, 0x00, 0x00,
+    0x00, 0xFF, 0x00, 0x0F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x0B, 0x00, 0x00, 0x02, 0x0A, 0x21,
+    0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0xC8, 0x00, 0x00, 0x38, 0xFF, 0x01, 0x00, 0x08, 0x00,
+    0x00, 0x01, 0xCC, 0x0F, 0x01, 0xF1, 0x0D, 0x01, 0x68, 0x00, 0x80, 0x08, 0xB8, 0x00, 0x00, 0x00,
+    0x00, 0x0F, 0x89, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x0F, 0x0D, 0x0E, 0x0E, 0x00,
+    0x00, 0x02, 0xC7, 0xFF, 0x9B, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+];
+
+/// Raw range status to the status of ST's driver, 0 valid, 2 signal fail,
+/// 3 and 13 minimum range, 4 out of bounds, 7 wrap around, 5 hardware fail,
+/// 10 the synchronisation of the first range, 11 valid with merged pulses
+/// and 255 unknown.
+const RANGE_STATUS: [u8; 24] = [
+    255, 255, 255, 5, 2, 4, 1, 7, 3, 0, 255, 255, 9, 13, 255, 255, 255, 255, 10, 6, 255, 255, 11,
+    12,
+];
+
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub enum DistanceMode {
+    /// Up to 1.3 m, less disturbed by ambient light.
+    Short,
+    /// Up to 4 m in the dark, about 1.3 m in bright light.
+    #[default]
+    Long,
+}
+
+/// The timing budgets ST has register values for.
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub enum TimingBudget {
+    /// Short distance mode only, `Ms20` in long distance mode.
+    Ms15,
+    Ms20,
+    Ms33,
+    #[default]
+    Ms50,
+    Ms100,
+    Ms200,
+    Ms500,
+}
+
+impl TimingBudget {
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(match self {
+            TimingBudget::Ms15 => 15,
+            TimingBudget::Ms20 => 20,
+            TimingBudget::Ms33 => 33,
+            TimingBudget::Ms50 => 50,
+            TimingBudget::Ms100 => 100,
+            TimingBudget::Ms200 => 200,
+            TimingBudget::Ms500 => 500,
+        })
+    }
+
+    /// Timeouts of the two ranging phases A and B.
+    fn macro_periods(&self, mode: DistanceMode) -> (u16, u16) {
+        match (mode, self) {
+            (DistanceMode::Short, TimingBudget::Ms15) => (0x001D, 0x0027),
+            (DistanceMode::Short, TimingBudget::Ms20) => (0x0051, 0x006E),
+            (DistanceMode::Short, TimingBudget::Ms33) => (0x00D6, 0x006E),
+            (DistanceMode::Short, TimingBudget::Ms50) => (0x01AE, 0x01E8),
+            (DistanceMode::Short, TimingBudget::Ms100) => (0x02E1, 0x0388),
+            (DistanceMode::Short, TimingBudget::Ms200) => (0x03E1, 0x0496),
+            (DistanceMode::Short, TimingBudget::Ms500) => (0x0591, 0x05C1),
+            (DistanceMode::Long, TimingBudget::Ms15 | TimingBudget::Ms20) => (0x001E, 0x0022),
+            (DistanceMode::Long, TimingBudget::Ms33) => (0x0060, 0x006E),
+            (DistanceMode::Long, TimingBudget::Ms50) => (0x00AD, 0x00C6),
+            (DistanceMode::Long, TimingBudget::Ms100) => (0x01CC, 0x01EA),
+            (DistanceMode::Long, TimingBudget::Ms200) => (0x02D9, 0x02F8),
+            (DistanceMode::Long, TimingBudget::Ms500) => (0x048F, 0x04A4),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub struct Vl53l1xConfig {
+    pub distance_mode: DistanceMode,
+    /// Time for one measurement, longer is more precise and reaches farther.
+    pub timing_budget: TimingBudget,
+}
+
+/// ST VL53L1X time-of-flight distance sensor on an async I2C bus, up to 4 m.
+///
+/// Follows ST's ultra lite driver. The chip always ranges at a fixed period,
+/// `try_measure` starts it at the timing budget if `try_start_continuous` was not called.
+#[derive(Debug, Clone, defmt::Format)]
+pub struct Vl53l1x<I: I2c, D: DelayNs = Delay, C: Clock = SystemClock> {
+    i2c: I,
+    delay: D,
+    clock: C,
+    address: u8,
+    config: Vl53l1xConfig,
+    ranging: bool,
+}
+
+impl<I: I2c> Vl53l1x<I> {
+    pub fn new(i2c: I) -> Self {
+        Self {
+            i2c,
+            delay: Delay,
+            clock: SystemClock,
+            address: DEFAULT_ADDRESS,
+            config: Vl53l1xConfig::default(),
+            ranging: false,
+        }
+    }
+}
+
+impl<I: I2c, D: DelayNs, C: Clock> Vl53l1x<I, D, C> {
+    /// The delay used to poll the chip.
+    pub fn with_delay<E: DelayNs>(self, delay: E) -> Vl53l1x<I, E, C> {
+        Vl53l1x {
+            i2c: self.i2c,
+            delay,
+            clock: self.clock,
+            address: self.address,
+            config: self.config,
+            ranging: self.ranging,
+        }
+    }
+
+    /// The clock that timestamps the ranges.
+    pub fn with_clock<K: Clock>(self, clock: K) -> Vl53l1x<I, D, K> {
+        Vl53l1x {
+            i2c: self.i2c,
+            delay: self.delay,
+            clock,
+            address: self.address,
+            config: self.config,
+            ranging: self.ranging,
+        }
+    }
+
+    pub fn with_config(mut self, config: Vl53l1xConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn config(&self) -> Vl53l1xConfig {
+        self.config
+    }
+
+    /// Checks the chip id, waits for it to boot, loads the default
+    /// configuration and runs one measurement to calibrate it.
+    pub async fn try_init(&mut self) -> Result<(), Error> {
+        let id = self.read_u8(reg::IDENTIFICATION_MODEL_ID).await?;
+        if id != MODEL_ID {
+            return Err(Error::UnknownDevice(id));
+        }
+        self.wait_for(reg::FIRMWARE_SYSTEM_STATUS).await?;
+
+        self.write(CONFIGURATION_START, &DEFAULT_CONFIGURATION)
+            .await?;
+
+        // the first measurement calibrates the VHV
+        self.write(reg::SYSTEM_MODE_START, &[0x40]).await?;
+        self.wait_for(reg::GPIO_TIO_HV_STATUS).await?;
+        self.write(reg::SYSTEM_INTERRUPT_CLEAR, &[0x01]).await?;
+        self.write(reg::SYSTEM_MODE_START, &[0x00]).await?;
+        // two VHV bounds, starting from the calibrated temperature
+        self.write(reg::VHV_CONFIG_TIMEOUT_MACROP_LOOP_BOUND, &[0x09])
+            .await?;
+        self.write(reg::VHV_CONFIG_INIT, &[0x00]).await?;
+
+        // sets the timing budget as well
+        self.try_set_distance_mode(self.config.distance_mode).await
+    }
+
+    pub async fn try_set_distance_mode(&mut self, mode: DistanceMode) -> Result<(), Error> {
+        let (phasecal, vcsel_a, vcsel_b, valid_phase, woi, initial_phase) = match mode {
+            DistanceMode::Short => (0x14, 0x07, 0x05, 0x38, 0x0705, 0x0606),
+            DistanceMode::Long => (0x0A, 0x0F, 0x0D, 0xB8, 0x0F0D, 0x0E0E),
+        };
+        self.write(reg::PHASECAL_CONFIG_TIMEOUT_MACROP, &[phasecal])
+            .await?;
+        self.write(reg::RANGE_CONFIG_VCSEL_PERIOD_A, &[vcsel_a])
+            .await?;
+        self.write(reg::RANGE_CONFIG_VCSEL_PERIOD_B, &[vcsel_b])
+            .await?;
+        self.write(reg::RANGE_CONFIG_VALID_PHASE_HIGH, &[valid_phase])
+            .await?;
+        self.write(reg::SD_CONFIG_WOI_SD0, &u16::to_be_bytes(woi))
+            .await?;
+        self.write(
+            reg::SD_CONFIG_INITIAL_PHASE_SD0,
+            &u16::to_be_bytes(initial_phase),
+        )
+        .await?;
+        self.config.distance_mode = mode;
+        // the timeouts differ between the modes
+        self.try_set_timing_budget(self.config.timing_budget).await
+    }
+
+    /// `Ms15` turns into `Ms20` in long distance mode, which has no shorter timeouts.
+    pub async fn try_set_timing_budget(&mut self, budget: TimingBudget) -> Result<(), Error> {
+        let budget = match (self.config.distance_mode, budget) {
+            (DistanceMode::Long, TimingBudget::Ms15) => TimingBudget::Ms20,
+            _ => budget,
+        };
+        let (a, b) = budget.macro_periods(self.config.distance_mode);
+        self.write(reg::RANGE_CONFIG_TIMEOUT_MACROP_A_HI, &a.to_be_bytes())
+            .await?;
+        self.write(reg::RANGE_CONFIG_TIMEOUT_MACROP_B_HI, &b.to_be_bytes())
+            .await?;
+        self.config.timing_budget = budget;
+        Ok(())
+    }
+
+    /// Starts ranging every `period`, at least every timing budget.
+    pub async fn try_start_continuous(&mut self, period: Duration) -> Result<(), Error> {
+        let period_ms = period.max(self.config.timing_budget.duration()).as_millis() as u32;
+        // the period is counted in oscillator ticks
+        let osc = self.read_u16(reg::RESULT_OSC_CALIBRATE_VAL).await? & 0x3FF;
+        let ticks = osc as u32 * period_ms * 1075 / 1000;
+        self.write(reg::SYSTEM_INTERMEASUREMENT_PERIOD, &ticks.to_be_bytes())
+            .await?;
+        self.write(reg::SYSTEM_MODE_START, &[0x40]).await?;
+        self.ranging = true;
+        Ok(())
+    }
+
+    pub async fn try_stop_continuous(&mut self) -> Result<(), Error> {
+        self.write(reg::SYSTEM_MODE_START, &[0x00]).await?;
+        self.ranging = false;
+        Ok(())
+    }
+
+    /// Polls `register` every millisecond until its lowest bit is set, or `Error::Timeout`.
+    ///
+    /// Data ready shows there as the interrupt is active high.
+    async fn wait_for(&mut self, register: u16) -> Result<(), Error> {
+        for _ in 0..POLL_TIMEOUT_MS {
+            if self.read_u8(register).await? & 0x01 != 0 {
+                return Ok(());
+            }
+            self.delay.delay_ms(1).await;
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Waits for the next range, returning the status of ST's driver and the
+    /// status, ambient rate, distance and signal rate registers.
+    async fn read_result(&mut self) -> Result<(u8, [u8; 17], Instant), Error> {
+        self.wait_for(reg::GPIO_TIO_HV_STATUS).await?;
+        let mut result = [0; 17];
+        self.read_into(reg::RESULT_RANGE_STATUS, &mut result)
+            .await?;
+        self.write(reg::SYSTEM_INTERRUPT_CLEAR, &[0x01]).await?;
+        let timestamp = self.clock.now();
+
+        let status = RANGE_STATUS
+            .get((result[0] & 0x1F) as usize)
+            .copied()
+            .unwrap_or(255);
+        Ok((status, result, timestamp))
+    }
+
+    async fn read_into(&mut self, register: u16, buf: &mut [u8]) -> Result<(), Error> {
+        self.i2c
+            .write_read(self.address, &register.to_be_bytes(), buf)
+            .await
+            .map_err(Error::i2c)
+    }
+
+    async fn read_u8(&mut self, register: u16) -> Result<u8, Error> {
+        let mut buf = [0];
+        self.read_into(register, &mut buf).await?;
+        Ok(buf[0])
+    }
+
+    async fn read_u16(&mut self, register: u16) -> Result<u16, Error> {
+        let mut buf = [0; 2];
+        self.read_into(register, &mut buf).await?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    /// Writes `data` to `register` and the ones after it.
+    async fn write(&mut self, register: u16, data: &[u8]) -> Result<(), Error> {
+        let mut buf = [0; 2 + DEFAULT_CONFIGURATION.len()];
+        buf[..2].copy_from_slice(&register.to_be_bytes());
+        buf[2..2 + data.len()].copy_from_slice(data);
+        self.i2c
+            .write(self.address, &buf[..2 + data.len()])
+            .await
+            .map_err(Error::i2c)
+    }
+}
+
+impl<I: I2c, D: DelayNs, C: Clock> Addressable for Vl53l1x<I, D, C> {
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    async fn try_set_address(&mut self, address: u8) -> Result<(), Error> {
+        let address = check_address(address)?;
+        self.write(reg::I2C_SLAVE_DEVICE_ADDRESS, &[address])
+            .await?;
+        self.address = address;
+        Ok(())
+    }
+}
+
+impl<I: I2c, D: DelayNs, C: Clock> RangeSensor for Vl53l1x<I, D, C> {
+    async fn try_measure(&mut self) -> Result<Range, RangeError> {
+        if !self.ranging {
+            self.try_start_continuous(Duration::from_millis(0)).await?;
+        }
+        let (mut status, mut result, mut timestamp) = self.read_result().await?;
+        if status == 10 {
+            // the first range after starting only synchronises the chip
+            (status, result, timestamp) = self.read_result().await?;
+        }
+        match status {
+            0 | 11 => {}
+            // sigma, signal or too little signal for a target
+            1 | 2 | 12 => return Err(RangeError::NoEcho),
+            // clipped at or below the minimum range
+            3 | 13 => return Err(RangeError::TooClose),
+            // out of bounds, wrapped around, or not checked for wrapping yet
+            4 | 6 | 7 => return Err(RangeError::TooFar),
+            // hardware fail, unknown and the processing failures
+            status => return Err(RangeError::Driver(Error::DeviceFault(status))),
+        }
+        let ambient = u16::from_be_bytes([result[7], result[8]]) as f32;
+        let distance_mm = u16::from_be_bytes([result[13], result[14]]) as f32;
+        let signal = u16::from_be_bytes([result[15], result[16]]) as f32;
+        Ok(Range {
+            distance_mm,
+            timestamp,
+            quality: signal_quality(signal, ambient),
+        })
+    }
+}
@@ -0,0 +1,53 @@
+use core::convert::Infallible;
+
+use embedded_hal::{digital, i2c, pwm};
+
+/// Errors reported by the drivers, carrying the HAL error kind that caused them,
+/// and by the motions built on top of them.
+///
+/// Public methods that can fail are named `try_*`, async bus drivers, sensor
+/// traits and motions included, whether they return this error or one that
+/// converts into it. A plain name without the prefix is only kept next to its
+/// `try_*` twin, for HALs whose errors are `Infallible`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    Pin(digital::ErrorKind),
+    Pwm(pwm::ErrorKind),
+    I2c(i2c::ErrorKind),
+    /// A chip answered with an id the driver does not know.
+    UnknownDevice(u8),
+    /// A chip reported a fault of its own, with the chip's status code.
+    DeviceFault(u8),
+    /// An I2C address outside 0x08..=0x77, the range left for devices.
+    InvalidAddress(u8),
+    /// A motion or a chip did not finish in the time it should have taken, e.g. a stalled wheel.
+    Timeout,
+}
+
+impl Error {
+    pub fn pin(err: impl digital::Error) -> Self {
+        Self::Pin(err.kind())
+    }
+
+    pub fn pwm(err: impl pwm::Error) -> Self {
+        Self::Pwm(err.kind())
+    }
+
+    pub fn i2c(err: impl i2c::Error) -> Self {
+        Self::I2c(err.kind())
+    }
+}
+
+impl From<Infallible> for Error {
+    fn from(err: Infallible) -> Self {
+        match err {}
+    }
+}
+
+/// Unwraps the result of a `try_*` call whose pins all have `Infallible` errors.
+pub(crate) fn infallible<T>(result: Result<T, Error>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(_) => unreachable!("infallible pin reported an error"),
+    }
+}
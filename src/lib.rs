@@ -1,7 +1,12 @@
 #![no_main]
 #![no_std]
 
+pub mod calibration;
+pub mod control;
+pub mod drive;
 pub mod drivers;
+pub mod error;
+pub mod nav;
 
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
@@ -19,10 +24,19 @@ use panic_probe as _;
 #[cfg(test)]
 #[defmt_test::tests]
 mod unit_tests {
-    use defmt::assert;
+    use defmt::{assert, assert_eq};
+
+    use core::cell::Cell;
+    use core::convert::Infallible;
+
+    use crate::drivers::adc::AnalogInputs;
+    use crate::drivers::motor::Motor;
 
     use embedded_hal::digital::ErrorKind;
-    use embedded_hal::digital::{ErrorType, InputPin};
+    use embedded_hal::digital::{ErrorType, InputPin, OutputPin};
+    use embedded_hal::pwm::SetDutyCycle;
+    use embedded_hal_async::delay::DelayNs;
+    use num_traits::float::FloatCore;
 
     #[test]
     fn line_sensor() {
@@ -34,6 +48,1815 @@ mod unit_tests {
         assert!(!sensor2.is_on_line());
     }
 
+    #[test]
+    fn line_sensor_errors() {
+        use crate::drivers::line_sensor::{LineArray, LineSensor};
+        use crate::error::Error;
+        let mut sensor = LineSensor::new(FailingPin);
+        assert_eq!(sensor.try_is_on_line(), Err(Error::Pin(ErrorKind::Other)));
+        let mut array = LineArray::new([LineSensor::new_invert(FailingPin)], 8.0);
+        assert_eq!(array.try_read(), Err(Error::Pin(ErrorKind::Other)));
+    }
+
+    #[test]
+    fn line_array_reading() {
+        use crate::drivers::line_sensor::{LineArray, LinePos, LineSensor};
+        let mut array = LineArray::new(
+            [false, false, true, true, false].map(|on| LineSensor::new(MockPin::new(on))),
+            8.0,
+        );
+        let reading = array.read();
+        assert_eq!(reading.mask, 0b01100);
+        assert_eq!(reading.active, 2);
+        assert_eq!(reading.position_mm, 4.0);
+        assert!(!reading.all_on && !reading.none_on);
+        assert_eq!(reading.line_pos(), LinePos::Middle);
+
+        let mut all = LineArray::new([true; 3].map(|on| LineSensor::new(MockPin::new(on))), 8.0);
+        assert!(all.read().all_on);
+        assert_eq!(all.read().line_pos(), LinePos::NoLine);
+    }
+
+    #[test]
+    fn tripple_line_sensor_positions() {
+        use crate::drivers::line_sensor::{LinePos, TrippleLineSensor};
+        let cases = [
+            ((true, false, false), LinePos::Lefter),
+            ((true, true, false), LinePos::Left),
+            ((false, true, false), LinePos::Middle),
+            ((false, true, true), LinePos::Right),
+            ((false, false, true), LinePos::Righter),
+            ((true, false, true), LinePos::NoLine),
+            ((true, true, true), LinePos::NoLine),
+            ((false, false, false), LinePos::NoLine),
+        ];
+        for ((l, m, r), expected) in cases {
+            let mut sensor =
+                TrippleLineSensor::new(MockPin::new(l), MockPin::new(m), MockPin::new(r));
+            assert_eq!(sensor.read(), expected);
+        }
+    }
+
+    #[test]
+    fn analog_line_array_calibration() {
+        use crate::drivers::line_sensor::AnalogLineArray;
+        let mut array = AnalogLineArray::new(MockAdc::new([100, 100, 100]), 10.0);
+        assert_eq!(array.read_calibrated(), [0, 0, 0]);
+
+        array.calibrate();
+        array.inputs_mut().values = [3100, 3100, 3100];
+        array.calibrate();
+
+        array.inputs_mut().values = [100, 1600, 3100];
+        assert_eq!(array.read_calibrated(), [0, 500, 1000]);
+    }
+
+    #[test]
+    fn analog_line_array_position() {
+        use crate::drivers::line_sensor::{AnalogLineArray, ChannelCalibration};
+        let mut array = AnalogLineArray::new(MockAdc::new([0, 0, 0, 0, 0]), 10.0);
+        array.set_calibration([ChannelCalibration { min: 0, max: 1000 }; 5]);
+
+        array.inputs_mut().values = [0, 0, 1000, 0, 0];
+        let reading = array.read();
+        assert_eq!(reading.position_mm, 0.0);
+        assert_eq!(reading.mask, 0b00100);
+
+        array.inputs_mut().values = [0, 0, 1000, 1000, 0];
+        assert_eq!(array.read().position_mm, 5.0);
+
+        array.inputs_mut().values = [0, 0, 0, 1000, 600];
+        let reading = array.read();
+        assert!((reading.position_mm - 13.75).abs() < 1e-3);
+
+        // lost on the right, the position sticks to that edge
+        array.inputs_mut().values = [0; 5];
+        let reading = array.read();
+        assert!(reading.none_on);
+        assert_eq!(reading.position_mm, 20.0);
+    }
+
+    #[test]
+    fn spin_calibration() {
+        use crate::calibration::{SpinCalibrationConfig, calibrate_spin};
+        use crate::drive::DifferentialDrive;
+        use embassy_time::Duration;
+
+        let mut inputs = ScriptedInputs {
+            samples: &[[0, 1, 5], [0, 1, 5], [1, 0, 5], [0, 1, 5]],
+            next: 0,
+        };
+        let left_pins = MotorPins::default();
+        let right_pins = MotorPins::default();
+        let mut drive = DifferentialDrive::new(left_pins.motor(1000), right_pins.motor(1000));
+        let config = SpinCalibrationConfig {
+            speed: 30.0,
+            duration: Duration::from_millis(80),
+            sample_interval: Duration::from_millis(10),
+        };
+
+        let calibration = embassy_futures::block_on(calibrate_spin(
+            &mut inputs,
+            &mut drive,
+            &mut NoopDelay,
+            config,
+        ))
+        .unwrap();
+
+        assert_eq!(inputs.next, 8);
+        assert_eq!(left_pins.duty.get(), 0);
+        assert_eq!(right_pins.duty.get(), 0);
+
+        let [first, second, third] = calibration.sensors;
+        assert!(first.line_reads_high && first.is_on_line(1) && !first.is_on_line(0));
+        assert!(!second.line_reads_high && second.is_on_line(0) && !second.is_on_line(1));
+        assert!(!third.is_valid());
+        assert!(!calibration.is_valid());
+    }
+
+    #[test]
+    fn motor_stop_modes() {
+        use crate::drivers::motor::{Bridge, StopMode};
+        let pins = MotorPins::default();
+        let mut motor = pins.motor(1000);
+        motor.run(50.0);
+        assert_eq!(pins.state(), (500, true, false));
+        motor.brake();
+        assert_eq!(pins.state(), (1000, false, false));
+        motor.coast();
+        assert_eq!(pins.state(), (0, false, false));
+
+        let pins = MotorPins::default();
+        let mut motor = pins
+            .motor(1000)
+            .with_bridge(Bridge::Tb6612)
+            .with_stop_mode(StopMode::Brake);
+        motor.run(-50.0);
+        assert_eq!(pins.state(), (500, false, true));
+        motor.stop();
+        assert_eq!(pins.state(), (0, true, true));
+        assert_eq!(motor.get_speed(), 0.0);
+        motor.coast();
+        assert_eq!(pins.state(), (0, false, false));
+    }
+
+    #[test]
+    fn motor_duty_mapping() {
+        use crate::drivers::motor::duty_for_speed;
+        assert_eq!(duty_for_speed(50.0, 100), 50);
+        assert_eq!(duty_for_speed(12.34, 100), 12);
+        assert_eq!(duty_for_speed(12.34, 1000), 123);
+        assert_eq!(duty_for_speed(12.36, 1000), 124);
+        assert_eq!(duty_for_speed(0.5, 3999), 20);
+        assert_eq!(duty_for_speed(33.3, 65535), 21823);
+        assert_eq!(duty_for_speed(100.0, 65535), 65535);
+        assert_eq!(duty_for_speed(150.0, 3999), 3999);
+        assert_eq!(duty_for_speed(-10.0, 3999), 0);
+
+        let pins = MotorPins::default();
+        let mut motor = pins.motor(3999);
+        assert!((motor.resolution() - 0.025).abs() < 1e-4);
+        motor.run(-42.42);
+        assert_eq!(pins.state(), (1696, false, true));
+    }
+
+    #[test]
+    fn motor_inverted_wiring() {
+        use crate::drivers::motor::{Direction, Motor};
+        let pins = MotorPins::default();
+        let mut motor = Motor::new_invert(
+            MockPwm {
+                duty: &pins.duty,
+                max_duty: 1000,
+            },
+            MockOutput {
+                high: &pins.forward,
+            },
+            MockOutput {
+                high: &pins.backward,
+            },
+            0.0,
+            Default::default(),
+        );
+        motor.run(40.0);
+        assert_eq!(pins.state(), (400, false, true));
+        assert_eq!(motor.get_dir(), Direction::Forward);
+        motor.run(-40.0);
+        assert_eq!(pins.state(), (400, true, false));
+        assert_eq!(motor.get_dir(), Direction::Backward);
+    }
+
+    #[test]
+    fn dual_pwm_motor() {
+        use crate::drivers::motor::{DcMotor, Decay, DualPwmMotor};
+        let (in1, in2) = (Cell::new(0), Cell::new(0));
+        let pwm = |duty| MockPwm {
+            duty,
+            max_duty: 1000,
+        };
+        let mut motor = DualPwmMotor::new(pwm(&in1), pwm(&in2));
+        let state = || (in1.get(), in2.get());
+
+        motor.try_run(30.0).unwrap();
+        assert_eq!(state(), (300, 0));
+        motor.try_run(-30.0).unwrap();
+        assert_eq!(state(), (0, 300));
+        motor.try_brake().unwrap();
+        assert_eq!(state(), (1000, 1000));
+        motor.try_coast().unwrap();
+        assert_eq!(state(), (0, 0));
+
+        let mut motor = DualPwmMotor::new_invert(pwm(&in1), pwm(&in2)).with_decay(Decay::Slow);
+        motor.try_run(30.0).unwrap();
+        assert_eq!(state(), (700, 1000));
+    }
+
+    #[test]
+    fn differential_drive_mixing() {
+        use crate::drive::{DifferentialDrive, desaturate};
+        assert_eq!(desaturate(200.0, -50.0), (100.0, -25.0));
+        assert_eq!(desaturate(80.0, -20.0), (80.0, -20.0));
+
+        let (left, right) = (MotorPins::default(), MotorPins::default());
+        let mut drive = DifferentialDrive::new(left.motor(1000), right.motor(1000));
+
+        drive.tank(200.0, 100.0);
+        assert_eq!(
+            (left.state(), right.state()),
+            ((1000, true, false), (500, true, false))
+        );
+        drive.arcade(50.0, 20.0);
+        assert_eq!(
+            (left.state(), right.state()),
+            ((300, true, false), (700, true, false))
+        );
+        // turning in place to the left
+        drive.arcade(0.0, 40.0);
+        assert_eq!(
+            (left.state(), right.state()),
+            ((400, false, true), (400, true, false))
+        );
+        drive.brake();
+        assert_eq!(
+            (left.state(), right.state()),
+            ((1000, false, false), (1000, false, false))
+        );
+    }
+
+    #[test]
+    fn differential_drive_kinematics() {
+        use crate::drive::{DifferentialDrive, DriveGeometry};
+        let (left, right) = (MotorPins::default(), MotorPins::default());
+        let mut drive = DifferentialDrive::new(left.motor(1000), right.motor(1000)).with_geometry(
+            DriveGeometry {
+                track_width_mm: 100.0,
+                max_wheel_speed_mm_s: 500.0,
+            },
+        );
+
+        assert_eq!(drive.wheel_speeds(250.0, 2.0), (150.0, 350.0));
+        drive.twist(250.0, 2.0);
+        assert_eq!((left.state().0, right.state().0), (300, 700));
+        // faster than the wheels can go, the turn rate keeps its share
+        drive.twist(500.0, 4.0);
+        assert_eq!((left.state().0, right.state().0), (429, 1000));
+
+        // 100 mm radius to the left
+        drive.curvature(40.0, 0.01);
+        assert_eq!((left.state().0, right.state().0), (200, 600));
+        drive.curvature(40.0, 0.0);
+        assert_eq!((left.state().0, right.state().0), (400, 400));
+    }
+
+    #[test]
+    fn servo_pulse_widths() {
+        use crate::drivers::servo::{Servo, ServoConfig};
+        let config = ServoConfig::default();
+        assert_eq!(config.pulse_us(0.0), 500.0);
+        assert_eq!(config.pulse_us(90.0), 1500.0);
+        assert_eq!(config.pulse_us(200.0), 2500.0);
+        assert_eq!(config.duty(180.0, 20_000), 2500);
+
+        // centered range with a trimmed horn, on a 100 Hz PWM
+        let config = ServoConfig {
+            period_us: 10_000.0,
+            min_angle: -90.0,
+            max_angle: 90.0,
+            center_trim_us: 50.0,
+            ..Default::default()
+        };
+        assert_eq!(config.center(), 0.0);
+        assert_eq!(config.pulse_us(0.0), 1550.0);
+        assert_eq!(config.pulse_us(-90.0), 550.0);
+        assert_eq!(config.pulse_us(90.0), 2500.0);
+        assert_eq!(config.duty(-45.0, 1000), 105);
+
+        let duty = Cell::new(0);
+        let mut servo = Servo::new(
+            MockPwm {
+                duty: &duty,
+                max_duty: 1000,
+            },
+            ServoConfig::default(),
+        );
+        servo.set_angle(90.0);
+        assert_eq!(duty.get(), 75);
+        servo.set_angle(-10.0);
+        assert_eq!(duty.get(), 25);
+    }
+
+    #[test]
+    fn servo_timed_motion() {
+        use crate::drivers::servo::{Servo, ServoConfig};
+        let duty = Cell::new(0);
+        let mut servo = Servo::new(
+            MockPwm {
+                duty: &duty,
+                max_duty: 20_000,
+            },
+            ServoConfig::default(),
+        );
+        assert_eq!(servo.angle(), None);
+
+        // unknown start: jump and wait for the longest possible travel
+        let mut delay = ElapsedDelay::default();
+        embassy_futures::block_on(servo.try_move_to_with(0.0, 90.0, &mut delay)).unwrap();
+        assert_eq!(servo.angle(), Some(0.0));
+        assert_eq!(delay.elapsed_us, 300_000 + 20_000);
+
+        // slower than the servo: 50 steps of 20 ms, then only the settle time
+        let mut delay = ElapsedDelay::default();
+        embassy_futures::block_on(servo.try_move_to_with(90.0, 90.0, &mut delay)).unwrap();
+        assert_eq!(servo.angle(), Some(90.0));
+        assert_eq!(duty.get(), 1500);
+        assert_eq!((delay.calls, delay.elapsed_us), (51, 1_000_000 + 20_000));
+
+        // instant command, the servo needs 60° / 600°/s to follow
+        let mut delay = ElapsedDelay::default();
+        embassy_futures::block_on(servo.try_move_to_with(30.0, f32::INFINITY, &mut delay)).unwrap();
+        assert_eq!(duty.get(), 833);
+        assert_eq!((delay.calls, delay.elapsed_us), (1, 100_000 + 20_000));
+    }
+
+    #[test]
+    fn continuous_servo() {
+        use crate::drive::DifferentialDrive;
+        use crate::drivers::servo::{ContinuousServo, ContinuousServoConfig};
+        let config = ContinuousServoConfig::default();
+        assert_eq!(config.pulse_us(0.0), 1500.0);
+        assert_eq!(config.pulse_us(-150.0), 1000.0);
+        assert_eq!(config.duty(50.0, 20_000), 1750);
+
+        let (left, right) = (Cell::new(0), Cell::new(0));
+        let pwm = |duty| MockPwm {
+            duty,
+            max_duty: 20_000,
+        };
+        let mut servo = ContinuousServo::new(pwm(&left), config);
+        servo.set_neutral_pulse_us(1520.0);
+        servo.stop();
+        assert_eq!(left.get(), 1520);
+
+        let mut drive =
+            DifferentialDrive::new(servo, ContinuousServo::new_invert(pwm(&right), config));
+        drive.try_tank(100.0, 50.0).unwrap();
+        assert_eq!((left.get(), right.get()), (2020, 1250));
+        drive.try_coast().unwrap();
+        assert_eq!((left.get(), right.get()), (0, 0));
+        drive.try_brake().unwrap();
+        assert_eq!((left.get(), right.get()), (1520, 1500));
+    }
+
+    #[test]
+    fn stepper_sequences() {
+        use crate::drivers::stepper::{FourWire, StepMode, StepperOutput};
+        assert_eq!(StepMode::Full.coils(-1), [true, false, false, true]);
+        assert_eq!(StepMode::Half.coils(9), [true, true, false, false]);
+
+        let coils: [Cell<bool>; 4] = Default::default();
+        let state = || coils.each_ref().map(Cell::get);
+        let mut output = FourWire::new(
+            coils.each_ref().map(|high| MockOutput { high }),
+            StepMode::Half,
+        );
+        output.try_step().unwrap();
+        output.try_step().unwrap();
+        assert_eq!(state(), [false, true, false, false]);
+        output.try_set_direction(false).unwrap();
+        output.try_step().unwrap();
+        output.try_step().unwrap();
+        output.try_step().unwrap();
+        assert_eq!(state(), [true, false, false, true]);
+        output.try_release().unwrap();
+        assert_eq!(state(), [false; 4]);
+    }
+
+    #[test]
+    fn stepper_motion() {
+        use crate::drivers::stepper::{MotionProfile, StepDir, Stepper};
+        let profile = MotionProfile {
+            start_speed: 100.0,
+            max_speed: 200.0,
+            acceleration: 15_000.0,
+        };
+        let intervals = [0, 1, 2, 3, 4].map(|index| profile.step_interval_us(index, 5));
+        assert_eq!(intervals, [10_000, 5_000, 5_000, 5_000, 10_000]);
+
+        let (step, dir) = (Cell::new(false), Cell::new(false));
+        let output = StepDir::new(MockOutput { high: &step }, MockOutput { high: &dir });
+        let mut stepper = Stepper::new(output, 8).with_profile(profile);
+
+        let mut delay = ElapsedDelay::default();
+        embassy_futures::block_on(stepper.try_move_steps_with(5, &mut delay)).unwrap();
+        // the steps and the DIR setup time
+        assert_eq!((stepper.position(), delay.elapsed_us), (5, 35_001));
+        assert!(dir.get() && !step.get());
+
+        embassy_futures::block_on(stepper.try_move_to_with(-2, &mut delay)).unwrap();
+        assert_eq!((stepper.position(), stepper.angle()), (-2, 270.0));
+        assert!(!dir.get());
+
+        // shortest way around, forward then backward
+        embassy_futures::block_on(stepper.try_move_to_angle_with(90.0, &mut delay)).unwrap();
+        assert_eq!((stepper.position(), stepper.angle()), (2, 90.0));
+        embassy_futures::block_on(stepper.try_move_to_angle_with(315.0, &mut delay)).unwrap();
+        assert_eq!((stepper.position(), stepper.angle()), (-1, 315.0));
+    }
+
+    #[test]
+    fn encoder_counting() {
+        use crate::drivers::encoder::{Encoder, QuadratureDecoder, SharedTicks, WrappingCounter};
+        let mut counter = WrappingCounter::new();
+        assert_eq!(counter.update(65_530), 0);
+        assert_eq!(counter.update(4), 10);
+        assert_eq!(counter.update(65_000), -530);
+        // the ticks before the first update count too
+        let mut counter = WrappingCounter::starting_at(65_530);
+        assert_eq!(counter.update(4), 10);
+
+        let mut decoder = QuadratureDecoder::new(false, false);
+        let forward = [(true, false), (true, true), (false, true), (false, false)];
+        let steps = forward.map(|(a, b)| decoder.update(a, b));
+        assert_eq!(steps, [1, 1, 1, 1]);
+        assert_eq!(decoder.update(false, true), -1);
+        assert_eq!(decoder.update(false, true), 0);
+        // both channels changed, the direction is unknown
+        assert_eq!(decoder.update(true, false), 0);
+
+        let shared = SharedTicks::new();
+        let mut reader = shared.reader();
+        shared.add(i32::MAX);
+        assert_eq!(reader.try_ticks().unwrap(), i32::MAX as i64);
+        shared.add(10);
+        assert_eq!(reader.try_ticks().unwrap(), i32::MAX as i64 + 10);
+    }
+
+    #[test]
+    fn wheel_encoder_speed() {
+        use crate::drivers::encoder::{WheelEncoder, WheelGeometry};
+        use embassy_time::{Duration, Instant};
+        let geometry = WheelGeometry {
+            counts_per_rev: 360.0,
+            wheel_diameter_mm: 65.0,
+        };
+        let mut wheel = WheelEncoder::new(MockEncoder { ticks: 100 }, geometry);
+        let start = Instant::from_millis(0);
+        assert_eq!(wheel.update_at(start), 0.0);
+
+        wheel.encoder_mut().ticks = 190;
+        let speed = wheel.update_at(start + Duration::from_millis(250));
+        assert_eq!(speed, 360.0);
+        assert_eq!(wheel.ticks(), 190);
+        // one revolution per second
+        assert!((wheel.speed_mm_s() - 65.0 * core::f32::consts::PI).abs() < 1e-3);
+
+        wheel.encoder_mut().ticks = 100;
+        wheel.update_at(start + Duration::from_millis(500));
+        assert_eq!(wheel.speed_ticks_s(), -360.0);
+        assert!((wheel.distance_mm() - 100.0 * geometry.mm_per_tick()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn velocity_feedforward() {
+        use crate::control::Feedforward;
+        let feedforward = Feedforward::from_points((30.0, 80.0), (70.0, 240.0)).unwrap();
+        assert_eq!(feedforward.duty_per_mm_s, 0.25);
+        assert_eq!(feedforward.static_duty, 10.0);
+        assert_eq!(feedforward.duty(-200.0), -60.0);
+        assert_eq!(feedforward.duty(0.0), 0.0);
+        // the same speed twice, or a wheel slowing down with more duty
+        assert!(Feedforward::from_points((30.0, 80.0), (70.0, 80.0)).is_none());
+        assert!(Feedforward::from_points((30.0, 240.0), (70.0, 80.0)).is_none());
+        assert!(Feedforward::from_points((30.0, f32::NAN), (70.0, 240.0)).is_none());
+    }
+
+    #[test]
+    fn velocity_motor_tracks_target() {
+        use crate::control::{Feedforward, VelocityMotor};
+        use crate::drivers::encoder::{WheelEncoder, WheelGeometry};
+        use embassy_time::Instant;
+        let geometry = WheelGeometry {
+            counts_per_rev: 1440.0,
+            wheel_diameter_mm: 65.0,
+        };
+        // calibrated on a weaker battery than the one now driving the wheel
+        let feedforward = Feedforward {
+            static_duty: 10.0,
+            duty_per_mm_s: 0.25,
+        };
+        let sim = SimWheel::new(5.0, 10.0);
+        let encoder = SimEncoder {
+            wheel: &sim,
+            mm_per_tick: geometry.mm_per_tick(),
+        };
+        let mut motor = VelocityMotor::new(
+            SimMotor(&sim),
+            WheelEncoder::new(encoder, geometry),
+            feedforward,
+        );
+
+        motor.set_target(300.0);
+        for step in 0..100 {
+            motor
+                .try_update_at(Instant::from_millis(step * 10))
+                .unwrap();
+            sim.advance(0.01);
+        }
+        assert!((sim.speed.get() - 300.0).abs() < 5.0);
+        // the open loop duty alone would run at 375 mm/s
+        assert!(sim.duty.get() < 72.0);
+
+        motor.set_target(0.0);
+        assert_eq!(
+            motor.try_update_at(Instant::from_millis(1000)).unwrap(),
+            0.0
+        );
+        assert_eq!(sim.duty.get(), 0.0);
+    }
+
+    #[test]
+    fn odometry_kinematics() {
+        use crate::nav::{Odometry, Pose};
+        use core::f32::consts::{FRAC_PI_2, PI};
+        let close = |pose: Pose, x: f32, y: f32, theta: f32| {
+            (pose.x_mm - x).abs() < 0.01
+                && (pose.y_mm - y).abs() < 0.01
+                && (pose.theta - theta).abs() < 1e-4
+        };
+
+        // straight line, the first update is only the reference
+        let mut odometry = Odometry::new(100.0);
+        assert_eq!(odometry.update(20.0, 20.0), Pose::default());
+        assert!(close(odometry.update(520.0, 520.0), 500.0, 0.0, 0.0));
+
+        // in place rotation by a quarter turn to the left
+        let mut odometry = Odometry::new(100.0);
+        odometry.update(0.0, 0.0);
+        let quarter = FRAC_PI_2 * 50.0;
+        assert!(close(
+            odometry.update(-quarter, quarter),
+            0.0,
+            0.0,
+            FRAC_PI_2
+        ));
+
+        // quarter circle of 200 mm radius to the left, in small steps or in one
+        let (inner, outer) = (FRAC_PI_2 * 150.0, FRAC_PI_2 * 250.0);
+        let mut odometry = Odometry::new(100.0);
+        odometry.update(0.0, 0.0);
+        for step in 1..=10 {
+            odometry.update(inner * step as f32 / 10.0, outer * step as f32 / 10.0);
+        }
+        assert!(close(odometry.pose(), 200.0, 200.0, FRAC_PI_2));
+        let mut odometry = Odometry::new(100.0);
+        odometry.update(0.0, 0.0);
+        assert!(close(
+            odometry.update(inner, outer),
+            200.0,
+            200.0,
+            FRAC_PI_2
+        ));
+
+        // headings wrap around
+        odometry.update(inner + 3.0 * quarter, outer - 3.0 * quarter);
+        assert!(
+            close(odometry.pose(), 200.0, 200.0, -PI) || close(odometry.pose(), 200.0, 200.0, PI)
+        );
+    }
+
+    #[test]
+    fn odometry_gyro_and_publishing() {
+        use crate::nav::{Odometry, PoseChannel};
+        use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+        // the wheels slipped and report no turn, the gyro saw 0.5 rad
+        let mut odometry = Odometry::new(100.0).with_gyro_weight(0.8);
+        odometry.update_with_gyro(0.0, 0.0, 0.0, 0.1);
+        let pose = odometry.update_with_gyro(0.0, 0.0, 5.0, 0.1);
+        assert!((pose.theta - 0.4).abs() < 1e-6);
+
+        let channel = PoseChannel::<NoopRawMutex, 2>::new();
+        let mut subscriber = channel.subscriber().unwrap();
+        odometry.publish(&channel.dyn_immediate_publisher());
+        assert_eq!(subscriber.try_next_message_pure(), Some(pose));
+        assert_eq!(subscriber.try_next_message_pure(), None);
+    }
+
+    #[test]
+    fn motion_speed_profile() {
+        use crate::nav::SpeedProfile;
+        let profile = SpeedProfile {
+            start_speed_mm_s: 100.0,
+            max_speed_mm_s: 300.0,
+            acceleration_mm_s2: 1000.0,
+        };
+        assert_eq!(profile.speed_at(0.0, 200.0), 100.0);
+        assert_eq!(profile.speed_at(15.0, 200.0), 200.0);
+        assert_eq!(profile.speed_at(100.0, 200.0), 300.0);
+        assert_eq!(profile.speed_at(185.0, 200.0), 200.0);
+        // 40 mm ramping up and down each, 120 mm at full speed
+        assert_eq!(profile.duration(200.0).as_millis(), 800);
+        // too short to reach full speed, peaks at 200 mm/s
+        assert_eq!(profile.duration(30.0).as_millis(), 200);
+    }
+
+    #[test]
+    fn motion_primitives_timed() {
+        use crate::drive::{DifferentialDrive, DriveGeometry};
+        use crate::nav::{Motion, MotionConfig, Progress, TimedModel};
+        use core::f32::consts::{FRAC_PI_2, PI};
+        let (left, right) = (MotorPins::default(), MotorPins::default());
+        let mut drive = DifferentialDrive::new(left.motor(1000), right.motor(1000)).with_geometry(
+            DriveGeometry {
+                track_width_mm: 100.0,
+                max_wheel_speed_mm_s: 400.0,
+            },
+        );
+        let model = TimedModel::new(drive.geometry());
+        let mut motion = Motion::new(model, MotionConfig::default()).with_delay(NoopDelay);
+
+        embassy_futures::block_on(motion.try_drive_distance(&mut drive, 300.0)).unwrap();
+        let travel = motion.progress_mut().try_travel().unwrap();
+        assert!((travel.distance_mm - 300.0).abs() <= 1.0 && travel.heading.abs() < 1e-6);
+        // braked at the end
+        assert_eq!(left.state(), (1000, false, false));
+
+        // the wheels travel 50 mm each way, 1 mm of tolerance is 0.02 rad
+        embassy_futures::block_on(motion.try_turn_by(&mut drive, -90.0)).unwrap();
+        let travel = motion.progress_mut().try_travel().unwrap();
+        assert!((travel.heading + FRAC_PI_2).abs() <= 0.02);
+        assert!((travel.distance_mm - 300.0).abs() <= 1.0);
+
+        embassy_futures::block_on(motion.try_arc(&mut drive, -200.0, 90.0)).unwrap();
+        let travel = motion.progress_mut().try_travel().unwrap();
+        assert!(travel.heading.abs() <= 0.03);
+        assert!((travel.distance_mm - (300.0 - 100.0 * PI)).abs() <= 2.0);
+    }
+
+    #[test]
+    fn motion_times_out_when_stalled() {
+        use crate::drive::DifferentialDrive;
+        use crate::drivers::encoder::WheelGeometry;
+        use crate::error::Error;
+        use crate::nav::motion::EncoderProgress;
+        use crate::nav::{Motion, MotionConfig};
+        let (left, right) = (MotorPins::default(), MotorPins::default());
+        let mut drive = DifferentialDrive::new(left.motor(1000), right.motor(1000));
+        let wheel = WheelGeometry {
+            counts_per_rev: 360.0,
+            wheel_diameter_mm: 65.0,
+        };
+        let encoders = EncoderProgress::new(
+            MockEncoder { ticks: 0 },
+            MockEncoder { ticks: 0 },
+            wheel,
+            130.0,
+        );
+        let mut motion = Motion::new(encoders, MotionConfig::default()).with_delay(NoopDelay);
+
+        let result = embassy_futures::block_on(motion.try_drive_distance(&mut drive, 100.0));
+        assert_eq!(result, Err(Error::Timeout));
+        assert_eq!(right.state(), (1000, false, false));
+    }
+
+    #[test]
+    fn imu_init_protocol() {
+        use crate::drivers::imu::{Dlpf, GyroRange, ImuConfig, Mpu6050};
+        use crate::error::Error;
+        let config = ImuConfig {
+            gyro_range: GyroRange::Dps500,
+            dlpf: Dlpf::Hz21,
+            sample_rate_divider: 4,
+            ..Default::default()
+        };
+        let mut imu = Mpu6050::new(MockI2c::new(&[&[0x68]]))
+            .with_address(0x69)
+            .with_config(config);
+        let mut delay = ElapsedDelay::default();
+        embassy_futures::block_on(imu.try_init(&mut delay)).unwrap();
+        assert_eq!(delay.elapsed_us, 100_000);
+
+        let expected = [
+            MockI2c::write(0x69, &[0x75]),
+            I2cOp::Read(0x69, 1),
+            MockI2c::write(0x69, &[0x6B, 0x80]),
+            MockI2c::write(0x69, &[0x6B, 0x01]),
+            MockI2c::write(0x69, &[0x19, 4, 4, 0x08, 0x00]),
+        ];
+        let mut i2c = MockI2c::new(&[&[0x42]]);
+        core::mem::swap(&mut i2c, imu.i2c_mut());
+        assert_eq!(i2c.log.as_slice(), expected.as_slice());
+
+        // a chip that is not an MPU-6050
+        assert_eq!(
+            embassy_futures::block_on(imu.try_init(&mut delay)),
+            Err(Error::UnknownDevice(0x42))
+        );
+    }
+
+    #[test]
+    fn imu_reading_and_yaw() {
+        use crate::drivers::imu::Mpu6050;
+        use embassy_time::{Duration, Instant};
+        // 1 g on z, 36.53 °C, 10 °/s around z, big endian
+        const SAMPLE: &[u8] = &[0, 0, 0, 0, 0x40, 0, 0, 0, 0, 0, 0, 0, 0x05, 0x1E];
+        let mut imu = Mpu6050::new(MockI2c::new(&[SAMPLE; 8]));
+
+        let reading = embassy_futures::block_on(imu.try_read()).unwrap();
+        assert_eq!(reading.accel_g, [0.0, 0.0, 1.0]);
+        assert_eq!(reading.temperature_c, 36.53);
+        let rate = 10.0 * core::f32::consts::PI / 180.0;
+        assert!((reading.gyro_rad_s[2] - rate).abs() < 1e-5);
+        assert_eq!(
+            imu.i2c_mut().log[..2],
+            [MockI2c::write(0x68, &[0x3B]), I2cOp::Read(0x68, 14)]
+        );
+
+        // standing still but reading 10 °/s: all of it is bias
+        let bias = embassy_futures::block_on(imu.try_calibrate_gyro(
+            4,
+            Duration::from_millis(5),
+            &mut NoopDelay,
+        ))
+        .unwrap();
+        assert!((bias[2] - rate).abs() < 1e-5);
+        // no samples, no new bias
+        let kept = embassy_futures::block_on(imu.try_calibrate_gyro(
+            0,
+            Duration::from_millis(5),
+            &mut NoopDelay,
+        ));
+        assert_eq!(kept, Ok(bias));
+
+        imu.set_gyro_bias([0.0; 3]);
+        let start = Instant::from_millis(0);
+        assert_eq!(
+            embassy_futures::block_on(imu.try_update_yaw_at(start)).unwrap(),
+            0.0
+        );
+        let yaw =
+            embassy_futures::block_on(imu.try_update_yaw_at(start + Duration::from_millis(500)));
+        assert!((yaw.unwrap() - rate / 2.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn heading_hold_trims_straight_runs() {
+        use crate::control::{HeadingHold, Pid};
+        use crate::drive::DifferentialDrive;
+        use crate::drivers::imu::SharedHeading;
+        use embassy_time::Instant;
+        let heading = SharedHeading::new();
+        let mut hold = HeadingHold::new(&heading).with_pid(Pid::new(100.0, 0.0, 0.0));
+        let now = Instant::from_millis(0);
+
+        // the heading at the start of the run is held
+        heading.set(0.5);
+        assert_eq!(hold.try_trim_at(40.0, 40.0, now).unwrap(), (40.0, 40.0));
+        assert_eq!(hold.target(), Some(0.5));
+        // drifting clockwise speeds up the right wheel
+        heading.set(0.375);
+        assert_eq!(hold.try_trim_at(40.0, 40.0, now).unwrap(), (27.5, 52.5));
+
+        // a turn is passed through and disengages the hold
+        assert_eq!(hold.try_trim_at(-30.0, 30.0, now).unwrap(), (-30.0, 30.0));
+        assert!(!hold.is_engaged());
+        // the next straight run holds the heading the turn ended at
+        heading.set(2.0);
+        assert_eq!(hold.try_trim_at(40.0, 40.0, now).unwrap(), (40.0, 40.0));
+        assert_eq!(hold.target(), Some(2.0));
+        // and so does a stop
+        assert_eq!(hold.try_trim_at(0.0, 0.0, now).unwrap(), (0.0, 0.0));
+        assert!(!hold.is_engaged());
+
+        let (left, right) = (MotorPins::default(), MotorPins::default());
+        let mut drive = DifferentialDrive::new(left.motor(1000), right.motor(1000));
+        hold.try_tank_at(&mut drive, 50.0, 50.0, now).unwrap();
+        heading.set(2.125);
+        hold.try_tank_at(&mut drive, 50.0, 50.0, now).unwrap();
+        assert_eq!(
+            (left.state(), right.state()),
+            ((625, true, false), (375, true, false))
+        );
+    }
+
+    #[test]
+    fn hcsr04_echo_to_range() {
+        use crate::drivers::hcsr04::Hcsr04Config;
+        use crate::drivers::range::RangeError;
+        use embassy_time::{Duration, Instant};
+        let config = Hcsr04Config {
+            temperature_c: 20.0,
+            ..Default::default()
+        };
+        assert!((config.speed_of_sound() - 0.343_2).abs() < 1e-4);
+
+        let at = Instant::from_millis(1000);
+        let range = config.try_range(Duration::from_micros(5828), at).unwrap();
+        assert!((range.distance_mm - 1000.1).abs() < 0.1);
+        assert_eq!((range.timestamp, range.quality), (at, 1.0));
+
+        // closer than 20 mm and farther than 4 m
+        assert_eq!(
+            config.try_range(Duration::from_micros(100), at),
+            Err(RangeError::TooClose)
+        );
+        assert_eq!(
+            config.try_range(Duration::from_millis(25), at),
+            Err(RangeError::TooFar)
+        );
+        // the pulse the sensor sends when nothing echoes
+        assert_eq!(
+            config.try_range(Duration::from_millis(38), at),
+            Err(RangeError::NoEcho)
+        );
+    }
+
+    #[test]
+    fn range_sensor_clearance() {
+        use crate::drivers::range::{RangeError, RangeSensor, clearance_mm};
+        use crate::error::Error;
+        use embedded_hal::digital::ErrorKind;
+        let mut sensor = ScriptedRanges::new(&[
+            Ok(250.0),
+            Err(RangeError::NoEcho),
+            Err(RangeError::Driver(Error::Pin(ErrorKind::Other))),
+        ]);
+        let first = embassy_futures::block_on(sensor.try_measure());
+        assert_eq!(clearance_mm(&first), 250.0);
+        assert_eq!(first.unwrap().timestamp.as_millis(), 60);
+        // nothing in front
+        let second = embassy_futures::block_on(sensor.try_measure());
+        assert_eq!(clearance_mm(&second), f32::INFINITY);
+        // a broken sensor sees nothing free
+        let third = embassy_futures::block_on(sensor.try_measure());
+        assert_eq!(clearance_mm(&third), 0.0);
+        // pressed against an obstacle, or past the end of the range
+        assert_eq!(clearance_mm(&Err(RangeError::TooClose)), 0.0);
+        assert_eq!(clearance_mm(&Err(RangeError::TooFar)), f32::INFINITY);
+    }
+
+    #[test]
+    fn tof_address_assignment() {
+        use crate::drivers::tof::{Addressable, Vl53l0x, assign_addresses};
+        use crate::error::Error;
+        let chips = [SimChip::new(1, 0x8A, &[]), SimChip::new(1, 0x8A, &[])];
+        let bus = SimBus(&chips);
+        let mut sensors = [
+            Vl53l0x::new(bus).with_delay(NoopDelay),
+            Vl53l0x::new(bus).with_delay(NoopDelay),
+        ];
+        // both chips answer at the default address until moved
+        let mut xshut = [
+            MockOutput {
+                high: &chips[0].enabled,
+            },
+            MockOutput {
+                high: &chips[1].enabled,
+            },
+        ];
+
+        embassy_futures::block_on(assign_addresses(
+            &mut sensors,
+            &mut xshut,
+            0x30,
+            &mut NoopDelay,
+        ))
+        .unwrap();
+        assert_eq!(
+            (chips[0].address.get(), chips[1].address.get()),
+            (0x30, 0x31)
+        );
+        assert!(chips[0].enabled.get() && chips[1].enabled.get());
+        assert_eq!((sensors[0].address(), sensors[1].address()), (0x30, 0x31));
+
+        // nothing is left at the default address
+        let mut stray = Vl53l0x::new(bus).with_delay(NoopDelay);
+        assert!(embassy_futures::block_on(stray.try_set_address(0x32)).is_err());
+
+        // addresses past 0x77 are refused before any chip is touched
+        assert_eq!(
+            embassy_futures::block_on(sensors[0].try_set_address(0x80)),
+            Err(Error::InvalidAddress(0x80))
+        );
+        assert_eq!(
+            embassy_futures::block_on(assign_addresses(
+                &mut sensors,
+                &mut xshut,
+                0x77,
+                &mut NoopDelay,
+            )),
+            Err(Error::InvalidAddress(0x78))
+        );
+        assert!(chips[0].enabled.get() && chips[1].enabled.get());
+        assert_eq!((sensors[0].address(), sensors[1].address()), (0x30, 0x31));
+    }
+
+    #[test]
+    fn vl53l0x_init_and_ranging() {
+        use crate::drivers::range::{RangeError, RangeSensor};
+        use crate::drivers::tof::Vl53l0x;
+        use crate::error::Error;
+        use embassy_time::{Duration, Instant};
+        // SPAD info ready and a measurement always waiting
+        let chip = SimChip::new(1, 0x8A, &[(0x83, 0x01), (0x13, 0x07)]);
+        chip.set(0xC0, &[0xEE]);
+        chip.set(0x91, &[0x3C]); // stop variable
+        chip.set(0x92, &[0x85]); // 5 aperture SPADs
+        chip.set(0xB0, &[0xFF; 6]);
+        chip.set(0xF8, &[0x00, 0x10]); // oscillator calibration
+        let mut tof = Vl53l0x::new(SimBus(core::slice::from_ref(&chip)))
+            .with_delay(NoopDelay)
+            .with_clock(FixedClock(Instant::from_millis(1_000)));
+
+        embassy_futures::block_on(tof.try_init()).unwrap();
+        // SPADs 12 to 16 are the reference
+        assert_eq!(chip.get::<6>(0xB0), [0x00, 0xF0, 0x01, 0x00, 0x00, 0x00]);
+        // final range timeout for 33 ms
+        assert_eq!(chip.get::<2>(0x71), [0x02, 0xA5]);
+        assert_eq!(chip.get::<1>(0x01), [0xE8]);
+
+        embassy_futures::block_on(tof.try_start_continuous(Duration::from_millis(100))).unwrap();
+        assert_eq!(chip.get::<4>(0x04), 1600u32.to_be_bytes());
+        assert_eq!(chip.get::<1>(0x00), [0x04]);
+        assert_eq!(chip.get::<1>(0x91), [0x3C]);
+
+        // valid, 3 MCPS signal, 1 MCPS ambient, 500 mm
+        chip.set(
+            0x14,
+            &[11 << 3, 0, 0, 0, 0, 0, 0x01, 0x80, 0x00, 0x80, 0x01, 0xF4],
+        );
+        chip.set(0x0B, &[0x00]);
+        let range = embassy_futures::block_on(tof.try_measure()).unwrap();
+        assert_eq!((range.distance_mm, range.quality), (500.0, 0.75));
+        assert_eq!(range.timestamp.as_millis(), 1_000);
+        assert_eq!(chip.get::<1>(0x0B), [0x01]);
+
+        // too little signal
+        chip.set(0x14, &[4 << 3]);
+        assert_eq!(
+            embassy_futures::block_on(tof.try_measure()),
+            Err(RangeError::NoEcho)
+        );
+        // a failing VCSEL is the chip's fault, not the target's
+        chip.set(0x14, &[1 << 3]);
+        assert_eq!(
+            embassy_futures::block_on(tof.try_measure()),
+            Err(RangeError::Driver(Error::DeviceFault(1)))
+        );
+
+        let wrong = SimChip::new(1, 0x8A, &[]);
+        wrong.set(0xC0, &[0xAA]);
+        let mut tof = Vl53l0x::new(SimBus(core::slice::from_ref(&wrong))).with_delay(NoopDelay);
+        assert_eq!(
+            embassy_futures::block_on(tof.try_init()),
+            Err(Error::UnknownDevice(0xAA))
+        );
+    }
+
+    #[test]
+    fn vl53l1x_init_and_ranging() {
+        use crate::drivers::range::{RangeError, RangeSensor};
+        use crate::drivers::tof::{DistanceMode, TimingBudget, Vl53l1x, Vl53l1xConfig};
+        use crate::error::Error;
+        use embassy_time::{Duration, Instant};
+        // booted, data always ready
+        let chip = SimChip::new(2, 0x0001, &[(0x00E5, 0x01), (0x0031, 0x01)]);
+        chip.set(0x010F, &[0xEA]);
+        chip.set(0x00DE, &[0x01, 0x00]); // oscillator calibration
+        let config = Vl53l1xConfig {
+            distance_mode: DistanceMode::Short,
+            timing_budget: TimingBudget::Ms50,
+        };
+        let mut tof = Vl53l1x::new(SimBus(core::slice::from_ref(&chip)))
+            .with_delay(NoopDelay)
+            .with_clock(FixedClock(Instant::from_millis(2_000)))
+            .with_config(config);
+
+        embassy_futures::block_on(tof.try_init()).unwrap();
+        // the default configuration, with the calibration measurement stopped
+        assert_eq!(chip.get::<4>(0x002D), [0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(chip.get::<2>(0x0086), [0x01, 0x00]);
+        assert_eq!(chip.get::<1>(0x0008), [0x09]);
+        // short distance mode, 50 ms
+        assert_eq!(chip.get::<1>(0x004B), [0x14]);
+        assert_eq!(chip.get::<1>(0x0060), [0x07]);
+        assert_eq!(chip.get::<4>(0x0078), [0x07, 0x05, 0x06, 0x06]);
+        assert_eq!(chip.get::<2>(0x005E), [0x01, 0xAE]);
+        assert_eq!(chip.get::<2>(0x0061), [0x01, 0xE8]);
+
+        // the period is at least the timing budget
+        embassy_futures::block_on(tof.try_start_continuous(Duration::from_millis(20))).unwrap();
+        assert_eq!(chip.get::<4>(0x006C), 13760u32.to_be_bytes());
+        assert_eq!(chip.get::<1>(0x0087), [0x40]);
+
+        // valid, 128 ambient, 1200 mm, 384 signal
+        let mut result = [0; 17];
+        result[0] = 9;
+        result[7..9].copy_from_slice(&[0x00, 0x80]);
+        result[13..15].copy_from_slice(&[0x04, 0xB0]);
+        result[15..17].copy_from_slice(&[0x01, 0x80]);
+        chip.set(0x0089, &result);
+        chip.set(0x0086, &[0x00]);
+        let range = embassy_futures::block_on(tof.try_measure()).unwrap();
+        assert_eq!((range.distance_mm, range.quality), (1200.0, 0.75));
+        assert_eq!(range.timestamp.as_millis(), 2_000);
+        assert_eq!(chip.get::<1>(0x0086), [0x01]);
+
+        // merged pulses are still a valid range
+        result[0] = 22;
+        chip.set(0x0089, &result);
+        let range = embassy_futures::block_on(tof.try_measure()).unwrap();
+        assert_eq!(range.distance_mm, 1200.0);
+        // the synchronisation of the first range is skipped, only once
+        chip.set(0x0089, &[18]);
+        assert_eq!(
+            embassy_futures::block_on(tof.try_measure()),
+            Err(RangeError::Driver(Error::DeviceFault(10)))
+        );
+
+        // signal fail and wrap around
+        chip.set(0x0089, &[4]);
+        assert_eq!(
+            embassy_futures::block_on(tof.try_measure()),
+            Err(RangeError::NoEcho)
+        );
+        chip.set(0x0089, &[7]);
+        assert_eq!(
+            embassy_futures::block_on(tof.try_measure()),
+            Err(RangeError::TooFar)
+        );
+        // a hardware failure, and a status missing from the table
+        chip.set(0x0089, &[3]);
+        assert_eq!(
+            embassy_futures::block_on(tof.try_measure()),
+            Err(RangeError::Driver(Error::DeviceFault(5)))
+        );
+        chip.set(0x0089, &[0]);
+        assert_eq!(
+            embassy_futures::block_on(tof.try_measure()),
+            Err(RangeError::Driver(Error::DeviceFault(255)))
+        );
+
+        // long distance mode has no 15 ms budget
+        embassy_futures::block_on(tof.try_set_distance_mode(DistanceMode::Long)).unwrap();
+        embassy_futures::block_on(tof.try_set_timing_budget(TimingBudget::Ms15)).unwrap();
+        assert_eq!(tof.config().timing_budget, TimingBudget::Ms20);
+
+        // the chip has not booted
+        let asleep = SimChip::new(2, 0x0001, &[]);
+        asleep.set(0x010F, &[0xEA]);
+        let mut tof = Vl53l1x::new(SimBus(core::slice::from_ref(&asleep))).with_delay(NoopDelay);
+        assert_eq!(
+            embassy_futures::block_on(tof.try_init()),
+            Err(Error::Timeout)
+        );
+    }
+
+    #[test]
+    fn range_filter_median_and_smoothing() {
+        use crate::drivers::range::{Range, RangeError, RangeFilter, RangeFilterConfig};
+        use embassy_time::Instant;
+        let at = |distance_mm, ms| {
+            Ok(Range {
+                distance_mm,
+                timestamp: Instant::from_millis(ms),
+                quality: 1.0,
+            })
+        };
+        let mut filter = RangeFilter::<3>::new(RangeFilterConfig {
+            max_rate_mm_s: 10_000.0,
+            max_rejections: 2,
+            smoothing: 0.5,
+        });
+
+        assert_eq!(filter.update(at(200.0, 0)).unwrap().distance_mm, 200.0);
+        // median of two, then halfway there
+        assert_eq!(filter.update(at(300.0, 100)).unwrap().distance_mm, 225.0);
+        // the spike only moves the median to the middle reading
+        let spiked = filter.update(at(900.0, 200)).unwrap();
+        assert_eq!(spiked.distance_mm, 262.5);
+        assert_eq!(spiked.timestamp.as_millis(), 200);
+        // failed measurements leave the state alone
+        assert!(filter.update(Err(RangeError::NoEcho)).is_none());
+        assert_eq!(filter.update(at(300.0, 300)).unwrap().distance_mm, 281.25);
+
+        filter.reset();
+        assert_eq!(filter.update(at(50.0, 400)).unwrap().distance_mm, 50.0);
+    }
+
+    #[test]
+    fn range_filter_rate_rejection() {
+        use crate::drivers::range::{Range, RangeFilter, RangeFilterConfig};
+        use embassy_time::Instant;
+        let at = |distance_mm, ms| {
+            Ok(Range {
+                distance_mm,
+                timestamp: Instant::from_millis(ms),
+                quality: 1.0,
+            })
+        };
+        let mut filter = RangeFilter::<3>::new(RangeFilterConfig {
+            max_rate_mm_s: 1000.0,
+            max_rejections: 2,
+            smoothing: 1.0,
+        });
+
+        assert_eq!(filter.update(at(500.0, 0)).unwrap().distance_mm, 500.0);
+        assert_eq!(filter.update(at(550.0, 100)).unwrap().distance_mm, 525.0);
+        // 1450mm in 100ms is a spurious echo
+        assert!(filter.update(at(2000.0, 200)).is_none());
+        // the rate is measured from the last accepted reading
+        assert_eq!(filter.update(at(560.0, 300)).unwrap().distance_mm, 550.0);
+
+        // something stepped in front, taken after two rejections
+        assert!(filter.update(at(100.0, 400)).is_none());
+        assert!(filter.update(at(100.0, 500)).is_none());
+        // and the old readings are dropped from the median
+        assert_eq!(filter.update(at(100.0, 600)).unwrap().distance_mm, 100.0);
+        assert_eq!(filter.update(at(120.0, 700)).unwrap().distance_mm, 110.0);
+    }
+
+    #[test]
+    fn shared_range_staleness() {
+        use crate::drivers::range::{Range, SharedRange};
+        use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+        use embassy_time::{Duration, Instant};
+        let shared = SharedRange::<NoopRawMutex>::new();
+        let max_age = Duration::from_millis(100);
+        assert!(shared.fresh_at(max_age, Instant::from_millis(0)).is_none());
+
+        shared.publish(Range {
+            distance_mm: 250.0,
+            timestamp: Instant::from_millis(1000),
+            quality: 1.0,
+        });
+        let range = shared
+            .fresh_at(max_age, Instant::from_millis(1050))
+            .unwrap();
+        assert_eq!(range.distance_mm, 250.0);
+        assert!(
+            shared
+                .fresh_at(max_age, Instant::from_millis(1100))
+                .is_some()
+        );
+        assert!(
+            shared
+                .fresh_at(max_age, Instant::from_millis(1101))
+                .is_none()
+        );
+
+        shared.clear();
+        assert!(
+            shared
+                .fresh_at(max_age, Instant::from_millis(1050))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn shared_range_clearance() {
+        use crate::drivers::range::{Range, RangeError, SharedRange};
+        use crate::error::Error;
+        use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+        use embassy_time::{Duration, Instant};
+        let shared = SharedRange::<NoopRawMutex>::new();
+        let max_age = Duration::from_millis(100);
+        let at = Instant::from_millis;
+        // nothing measured yet
+        assert_eq!(shared.clearance_at(max_age, at(0)), None);
+
+        shared.publish(Range {
+            distance_mm: 250.0,
+            timestamp: at(1000),
+            quality: 1.0,
+        });
+        // one failure does not hide a fresh range
+        shared.record(&Err(RangeError::Timeout));
+        assert_eq!(shared.last_error(), Some(RangeError::Timeout));
+        assert_eq!(shared.clearance_at(max_age, at(1050)), Some(250.0));
+        // a broken sensor blocks the way once the range is stale
+        assert_eq!(shared.clearance_at(max_age, at(1200)), Some(0.0));
+        shared.record(&Err(RangeError::Driver(Error::DeviceFault(5))));
+        assert_eq!(shared.clearance_at(max_age, at(1200)), Some(0.0));
+        // while missed pings mean nothing is in front
+        shared.record(&Err(RangeError::NoEcho));
+        assert_eq!(shared.clearance_at(max_age, at(1200)), Some(f32::INFINITY));
+
+        // a stale range without a failure is not trusted either
+        shared.publish(Range {
+            distance_mm: 300.0,
+            timestamp: at(2000),
+            quality: 1.0,
+        });
+        assert_eq!(shared.last_error(), None);
+        assert_eq!(shared.clearance_at(max_age, at(2200)), Some(0.0));
+
+        // a missed ping followed by a jump the filter rejected
+        shared.publish(Range {
+            distance_mm: 300.0,
+            timestamp: at(3000),
+            quality: 1.0,
+        });
+        shared.record(&Err(RangeError::NoEcho));
+        shared.record(&Ok(Range {
+            distance_mm: 50.0,
+            timestamp: at(3060),
+            quality: 1.0,
+        }));
+        assert_eq!(shared.last_error(), None);
+        assert_eq!(shared.clearance_at(max_age, at(3200)), Some(0.0));
+    }
+
+    #[test]
+    fn motor_deadband_and_gain() {
+        use crate::drivers::motor::MotorCalibration;
+        let calibration = MotorCalibration {
+            min_duty: 25.0,
+            gain: 0.5,
+        };
+        assert_eq!(calibration.apply(0.0), 0.0);
+        assert_eq!(calibration.apply(100.0), 62.5);
+        assert!(calibration.apply(0.01) > 25.0);
+
+        let pins = MotorPins::default();
+        let mut motor = pins.motor(1000).with_calibration(MotorCalibration {
+            min_duty: 20.0,
+            gain: 1.0,
+        });
+        motor.run(-50.0);
+        assert_eq!(pins.state(), (600, false, true));
+        assert_eq!(motor.get_speed(), 50.0);
+    }
+
+    #[test]
+    fn motor_start_duty_search() {
+        use crate::calibration::{StartDutyConfig, find_start_duty};
+        use crate::drivers::motor::MotorCalibration;
+        let pins = MotorPins::default();
+        let gain = MotorCalibration {
+            min_duty: 0.0,
+            gain: 0.9,
+        };
+        let mut motor = pins.motor(1000).with_calibration(gain);
+        let config = StartDutyConfig {
+            step: 5.0,
+            ..Default::default()
+        };
+        let found = embassy_futures::block_on(find_start_duty(
+            &mut motor,
+            || pins.duty.get() >= 230,
+            &mut NoopDelay,
+            config,
+        ))
+        .unwrap();
+        assert_eq!(
+            found,
+            Some(MotorCalibration {
+                min_duty: 25.0,
+                gain: 0.9
+            })
+        );
+        assert_eq!(motor.calibration(), gain);
+        assert_eq!(pins.duty.get(), 0);
+    }
+
+    #[test]
+    fn slew_limiter_reversal() {
+        use crate::control::SlewLimiter;
+        let mut limiter = SlewLimiter::new(100.0, 400.0);
+        assert_eq!(limiter.update(80.0, 0.5), 50.0);
+        assert_eq!(limiter.update(80.0, 0.5), 80.0);
+        // reversing ramps down at the reversal rate and stops at zero first
+        assert_eq!(limiter.update(-80.0, 0.1), 40.0);
+        assert_eq!(limiter.update(-80.0, 0.5), 0.0);
+        assert_eq!(limiter.update(-80.0, 0.1), -10.0);
+
+        let mut unlimited = SlewLimiter::new(f32::INFINITY, f32::INFINITY);
+        assert_eq!(unlimited.update(60.0, 0.0), 60.0);
+        assert_eq!(unlimited.update(-60.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn ramped_motor_emergency_stop() {
+        use crate::control::SlewLimiter;
+        use crate::drivers::motor::{RampedMotor, StopMode};
+        use embassy_time::Instant;
+        let pins = MotorPins::default();
+        let mut motor = RampedMotor::new(pins.motor(1000), SlewLimiter::new(100.0, 100.0));
+        assert_eq!(motor.run(100.0, Instant::from_millis(0)), 0.0);
+        assert_eq!(motor.update(Instant::from_millis(250)), 25.0);
+        assert_eq!(pins.state(), (250, true, false));
+
+        motor.emergency_stop(StopMode::Brake);
+        assert_eq!(motor.current(), 0.0);
+        assert_eq!(pins.state(), (1000, false, false));
+    }
+
+    #[test]
+    fn pid_proportional_and_limits() {
+        use crate::control::Pid;
+        let mut pid = Pid::new(2.0, 0.0, 0.0).with_output_limits(-5.0, 5.0);
+        assert_eq!(pid.update(1.0, 0.0, 0.01), 2.0);
+        assert_eq!(pid.update(10.0, 0.0, 0.01), 5.0);
+        assert_eq!(pid.update(-10.0, 0.0, 0.01), -5.0);
+    }
+
+    #[test]
+    fn pid_integral_uses_dt() {
+        use crate::control::Pid;
+        let mut pid = Pid::new(0.0, 1.0, 0.0);
+        pid.update(1.0, 0.0, 0.5);
+        pid.update(1.0, 0.0, 0.25);
+        assert!((pid.integral() - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn pid_anti_windup() {
+        use crate::control::{AntiWindup, Pid};
+        let mut clamped = Pid::new(1.0, 10.0, 0.0).with_output_limits(-1.0, 1.0);
+        for _ in 0..100 {
+            clamped.update(5.0, 0.0, 0.01);
+        }
+        assert_eq!(clamped.integral(), 0.0);
+
+        let mut back_calc = Pid::new(0.0, 10.0, 0.0)
+            .with_output_limits(-1.0, 1.0)
+            .with_anti_windup(AntiWindup::BackCalculation(50.0));
+        let mut free = Pid::new(0.0, 10.0, 0.0).with_anti_windup(AntiWindup::None);
+        for _ in 0..100 {
+            back_calc.update(5.0, 0.0, 0.01);
+            free.update(5.0, 0.0, 0.01);
+        }
+        assert!(back_calc.integral() < free.integral());
+        assert!(back_calc.integral() < 2.0);
+    }
+
+    #[test]
+    fn pid_integral_limits() {
+        use crate::control::Pid;
+        let mut pid = Pid::new(0.0, 2.0, 0.0)
+            .with_output_limits(-200.0, 200.0)
+            .with_integral_limits(-16.0, 16.0);
+        for _ in 0..20 {
+            pid.update(1.0, 0.0, 1.0);
+        }
+        assert_eq!(pid.integral(), 16.0);
+        assert_eq!(pid.update(1.0, 0.0, 1.0), 16.0);
+        // unwinds right away once the error flips
+        assert_eq!(pid.update(-1.0, 0.0, 1.0), 14.0);
+    }
+
+    #[test]
+    fn pid_derivative_on_measurement() {
+        use crate::control::Pid;
+        let mut pid = Pid::new(0.0, 0.0, 1.0);
+        pid.update(0.0, 0.0, 0.1);
+        // a setpoint step does not kick the derivative term
+        assert_eq!(pid.update(10.0, 0.0, 0.1), 0.0);
+        // a rising measurement pushes the output down
+        assert!((pid.update(10.0, 1.0, 0.1) + 10.0).abs() < 1e-4);
+
+        let mut filtered = Pid::new(0.0, 0.0, 1.0).with_derivative_filter(0.1);
+        filtered.update(0.0, 0.0, 0.1);
+        assert!((filtered.update(0.0, 1.0, 0.1) + 5.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn pid_update_at_elapsed_time() {
+        use crate::control::Pid;
+        use embassy_time::Instant;
+        let mut pid = Pid::new(0.0, 1.0, 0.0);
+        pid.update_at(1.0, 0.0, Instant::from_millis(100));
+        assert_eq!(pid.integral(), 0.0);
+        pid.update_at(1.0, 0.0, Instant::from_millis(600));
+        assert!((pid.integral() - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn pid_iteration_gains() {
+        use crate::control::Pid;
+        use embassy_time::{Duration, Instant};
+        let mut pid = Pid::from_iteration_gains(0.0, 0.5, 2.0, Duration::from_millis(100));
+        pid.update_at(1.0, 0.0, Instant::from_millis(0));
+        // one period adds the per iteration integral gain
+        pid.update_at(1.0, 0.0, Instant::from_millis(100));
+        assert!((pid.integral() - 0.5).abs() < 1e-6);
+        // and the derivative acts on the change per iteration
+        let output = pid.update_at(1.0, 1.0, Instant::from_millis(200));
+        assert!((output - (0.5 - 2.0)).abs() < 1e-4);
+    }
+
+    struct MockAdc<const N: usize> {
+        values: [u16; N],
+    }
+
+    impl<const N: usize> MockAdc<N> {
+        fn new(values: [u16; N]) -> Self {
+            Self { values }
+        }
+    }
+
+    impl<const N: usize> AnalogInputs<N> for MockAdc<N> {
+        type Error = Infallible;
+
+        fn try_read(&mut self) -> Result<[u16; N], Self::Error> {
+            Ok(self.values)
+        }
+    }
+
+    struct ScriptedInputs<const N: usize> {
+        samples: &'static [[u16; N]],
+        next: usize,
+    }
+
+    impl<const N: usize> AnalogInputs<N> for ScriptedInputs<N> {
+        type Error = Infallible;
+
+        fn try_read(&mut self) -> Result<[u16; N], Self::Error> {
+            let sample = self.samples[self.next % self.samples.len()];
+            self.next += 1;
+            Ok(sample)
+        }
+    }
+
+    struct NoopDelay;
+
+    impl DelayNs for NoopDelay {
+        async fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    /// A clock that never moves, `Instant::now` has no time driver on the host.
+    struct FixedClock(embassy_time::Instant);
+
+    impl crate::drivers::range::Clock for FixedClock {
+        fn now(&mut self) -> embassy_time::Instant {
+            self.0
+        }
+    }
+
+    struct MockEncoder {
+        ticks: i64,
+    }
+
+    impl crate::drivers::encoder::Encoder for MockEncoder {
+        type Error = Infallible;
+
+        fn try_ticks(&mut self) -> Result<i64, Self::Error> {
+            Ok(self.ticks)
+        }
+    }
+
+    // first order model of a gearmotor driving a wheel, shared by its motor and encoder halves
+    struct SimWheel {
+        gain: f32,     // mm/s per percent of duty above the deadband
+        deadband: f32, // percent
+        tau: f32,      // seconds
+        duty: Cell<f32>,
+        speed: Cell<f32>,
+        position: Cell<f32>,
+    }
+
+    impl SimWheel {
+        fn new(gain: f32, deadband: f32) -> Self {
+            Self {
+                gain,
+                deadband,
+                tau: 0.05,
+                duty: Cell::new(0.0),
+                speed: Cell::new(0.0),
+                position: Cell::new(0.0),
+            }
+        }
+
+        fn advance(&self, dt: f32) {
+            let duty = self.duty.get();
+            let steady = (self.gain * (duty.abs() - self.deadband).max(0.0)).copysign(duty);
+            for _ in 0..10 {
+                let speed = self.speed.get() + (steady - self.speed.get()) * dt / 10.0 / self.tau;
+                self.speed.set(speed);
+                self.position.set(self.position.get() + speed * dt / 10.0);
+            }
+        }
+    }
+
+    struct SimMotor<'a>(&'a SimWheel);
+
+    impl crate::drivers::motor::DcMotor for SimMotor<'_> {
+        fn try_run(&mut self, speed: f32) -> Result<(), crate::error::Error> {
+            self.0.duty.set(speed.clamp(-100.0, 100.0));
+            Ok(())
+        }
+
+        fn try_stop(&mut self) -> Result<(), crate::error::Error> {
+            self.try_run(0.0)
+        }
+
+        fn try_brake(&mut self) -> Result<(), crate::error::Error> {
+            self.try_run(0.0)
+        }
+
+        fn try_coast(&mut self) -> Result<(), crate::error::Error> {
+            self.try_run(0.0)
+        }
+    }
+
+    struct SimEncoder<'a> {
+        wheel: &'a SimWheel,
+        mm_per_tick: f32,
+    }
+
+    impl crate::drivers::encoder::Encoder for SimEncoder<'_> {
+        type Error = Infallible;
+
+        fn try_ticks(&mut self) -> Result<i64, Self::Error> {
+            Ok(FloatCore::floor(self.wheel.position.get() / self.mm_per_tick) as i64)
+        }
+    }
+
+    #[derive(Debug, PartialEq, defmt::Format)]
+    enum I2cOp {
+        // address, bytes padded with zeros, length
+        Write(u8, [u8; 8], usize),
+        Read(u8, usize),
+    }
+
+    // records every transaction and answers reads from a script
+    struct MockI2c {
+        log: heapless::Vec<I2cOp, 32>,
+        reads: &'static [&'static [u8]],
+        next_read: usize,
+    }
+
+    impl MockI2c {
+        fn new(reads: &'static [&'static [u8]]) -> Self {
+            Self {
+                log: heapless::Vec::new(),
+                reads,
+                next_read: 0,
+            }
+        }
+
+        fn write(address: u8, bytes: &[u8]) -> I2cOp {
+            let mut padded = [0; 8];
+            padded[..bytes.len()].copy_from_slice(bytes);
+            I2cOp::Write(address, padded, bytes.len())
+        }
+    }
+
+    impl embedded_hal::i2c::ErrorType for MockI2c {
+        type Error = Infallible;
+    }
+
+    impl embedded_hal_async::i2c::I2c for MockI2c {
+        async fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [embedded_hal::i2c::Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            use embedded_hal::i2c::Operation;
+            for operation in operations {
+                let op = match operation {
+                    Operation::Write(bytes) => Self::write(address, bytes),
+                    Operation::Read(buf) => {
+                        let reply = self.reads[self.next_read];
+                        self.next_read += 1;
+                        buf.copy_from_slice(reply);
+                        I2cOp::Read(address, buf.len())
+                    }
+                };
+                self.log.push(op).unwrap();
+            }
+            Ok(())
+        }
+    }
+
+    // a ToF chip on a simulated bus: registers auto-increment like on the real
+    // chips, register pages are not modelled
+    struct SimChip {
+        enabled: Cell<bool>, // XSHUT
+        address: Cell<u8>,
+        index_width: usize,
+        address_register: u16,
+        regs: core::cell::RefCell<[u8; 0x200]>,
+        // status registers the chip keeps at a value whatever is written
+        fixed: &'static [(u16, u8)],
+    }
+
+    impl SimChip {
+        fn new(index_width: usize, address_register: u16, fixed: &'static [(u16, u8)]) -> Self {
+            Self {
+                enabled: Cell::new(true),
+                address: Cell::new(0x29),
+                index_width,
+                address_register,
+                regs: core::cell::RefCell::new([0; 0x200]),
+                fixed,
+            }
+        }
+
+        fn set(&self, register: u16, bytes: &[u8]) {
+            let start = register as usize;
+            self.regs.borrow_mut()[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+
+        fn get<const N: usize>(&self, register: u16) -> [u8; N] {
+            let start = register as usize;
+            self.regs.borrow()[start..start + N].try_into().unwrap()
+        }
+
+        fn read(&self, register: u16) -> u8 {
+            match self.fixed.iter().find(|(fixed, _)| *fixed == register) {
+                Some((_, value)) => *value,
+                None => self.regs.borrow()[register as usize],
+            }
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    struct SimBus<'a>(&'a [SimChip]);
+
+    impl embedded_hal::i2c::ErrorType for SimBus<'_> {
+        type Error = embedded_hal::i2c::ErrorKind;
+    }
+
+    impl embedded_hal_async::i2c::I2c for SimBus<'_> {
+        async fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [embedded_hal::i2c::Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource, Operation};
+            let chip = self
+                .0
+                .iter()
+                .find(|chip| chip.enabled.get() && chip.address.get() == address)
+                .ok_or(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address))?;
+            let mut register = 0;
+            let mut new_address = None;
+            for operation in operations {
+                match operation {
+                    Operation::Write(bytes) => {
+                        let (index, data) = bytes.split_at(chip.index_width);
+                        register = index.iter().fold(0, |acc, &b| (acc << 8) | b as u16);
+                        for &byte in data {
+                            if register == chip.address_register {
+                                new_address = Some(byte);
+                            }
+                            chip.regs.borrow_mut()[register as usize] = byte;
+                            register += 1;
+                        }
+                    }
+                    Operation::Read(buf) => {
+                        for byte in buf.iter_mut() {
+                            *byte = chip.read(register);
+                            register += 1;
+                        }
+                    }
+                }
+            }
+            if let Some(address) = new_address {
+                chip.address.set(address);
+            }
+            Ok(())
+        }
+    }
+
+    // answers measurements from a script, one every 60 ms
+    struct ScriptedRanges {
+        script: &'static [Result<f32, crate::drivers::range::RangeError>],
+        next: usize,
+    }
+
+    impl ScriptedRanges {
+        fn new(script: &'static [Result<f32, crate::drivers::range::RangeError>]) -> Self {
+            Self { script, next: 0 }
+        }
+    }
+
+    impl crate::drivers::range::RangeSensor for ScriptedRanges {
+        async fn try_measure(
+            &mut self,
+        ) -> Result<crate::drivers::range::Range, crate::drivers::range::RangeError> {
+            self.next += 1;
+            let distance_mm = self.script[self.next - 1]?;
+            Ok(crate::drivers::range::Range {
+                distance_mm,
+                timestamp: embassy_time::Instant::from_millis(60 * self.next as u64),
+                quality: 1.0,
+            })
+        }
+    }
+
+    // adds up the time waited instead of waiting
+    #[derive(Default)]
+    struct ElapsedDelay {
+        elapsed_us: u64,
+        calls: u32,
+    }
+
+    impl DelayNs for ElapsedDelay {
+        async fn delay_ns(&mut self, ns: u32) {
+            self.elapsed_us += ns as u64 / 1000;
+            self.calls += 1;
+        }
+
+        async fn delay_us(&mut self, us: u32) {
+            self.elapsed_us += us as u64;
+            self.calls += 1;
+        }
+    }
+
+    // shared state of a mocked motor's pins, so it can be checked after the motor took them
+    #[derive(Default)]
+    struct MotorPins {
+        duty: Cell<u16>,
+        forward: Cell<bool>,
+        backward: Cell<bool>,
+    }
+
+    impl MotorPins {
+        fn motor(&self, max_duty: u16) -> Motor<MockPwm<'_>, MockOutput<'_>, MockOutput<'_>> {
+            Motor::new(
+                MockPwm {
+                    duty: &self.duty,
+                    max_duty,
+                },
+                MockOutput {
+                    high: &self.forward,
+                },
+                MockOutput {
+                    high: &self.backward,
+                },
+                0.0,
+                Default::default(),
+            )
+        }
+
+        fn state(&self) -> (u16, bool, bool) {
+            (self.duty.get(), self.forward.get(), self.backward.get())
+        }
+    }
+
+    struct MockPwm<'a> {
+        duty: &'a Cell<u16>,
+        max_duty: u16,
+    }
+
+    impl embedded_hal::pwm::ErrorType for MockPwm<'_> {
+        type Error = Infallible;
+    }
+
+    impl SetDutyCycle for MockPwm<'_> {
+        fn max_duty_cycle(&self) -> u16 {
+            self.max_duty
+        }
+
+        fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+            self.duty.set(duty);
+            Ok(())
+        }
+    }
+
+    struct MockOutput<'a> {
+        high: &'a Cell<bool>,
+    }
+
+    impl ErrorType for MockOutput<'_> {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for MockOutput<'_> {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.high.set(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.high.set(true);
+            Ok(())
+        }
+    }
+
     #[derive(Default)]
     struct MockPin {
         state: bool,
@@ -46,7 +1869,7 @@ mod unit_tests {
     }
 
     impl ErrorType for MockPin {
-        type Error = ErrorKind;
+        type Error = Infallible;
     }
 
     impl InputPin for MockPin {
@@ -58,4 +1881,20 @@ mod unit_tests {
             Ok(!self.state)
         }
     }
+
+    struct FailingPin;
+
+    impl ErrorType for FailingPin {
+        type Error = ErrorKind;
+    }
+
+    impl InputPin for FailingPin {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            Err(ErrorKind::Other)
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Err(ErrorKind::Other)
+        }
+    }
 }
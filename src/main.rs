@@ -3,7 +3,9 @@
 
 use core::fmt::Write;
 
-use clumsy_stm_bot::drivers::servo::Servo;
+use clumsy_stm_bot::drivers::hcsr04::{Hcsr04, Hcsr04Config};
+use clumsy_stm_bot::drivers::range::RangeSensor;
+use clumsy_stm_bot::drivers::servo::{Servo, ServoConfig};
 use clumsy_stm_bot::{self as _};
 use defmt::*;
 use embassy_executor::Spawner;
@@ -14,8 +16,7 @@ use embassy_stm32::timer::simple_pwm::{PwmPin, SimplePwm};
 use embassy_stm32::usart::Uart;
 use embassy_stm32::{bind_interrupts, peripherals, usart};
 use embassy_stm32::{exti::ExtiInput, gpio::Level};
-use embassy_time::{Delay, Duration, Instant, Timer};
-use hcsr04_async::{DistanceUnit, Hcsr04, TemperatureUnit};
+use embassy_time::{Duration, Timer};
 use heapless::String;
 use num_traits::float::FloatCore;
 use {defmt_rtt as _, panic_probe as _};
@@ -26,7 +27,8 @@ bind_interrupts!(struct Irqs {
 
 const FOV: usize = 180;
 const RESOLUTION: usize = 20;
-const TEMPERATURE: f64 = 22.0;
+const TEMPERATURE: f32 = 22.0;
+const SERVO_SPEED: f32 = 360.0; // deg/s
 
 const DISTANCE_MEASURE_INTERVAL: Duration = Duration::from_millis(50);
 
@@ -38,27 +40,17 @@ async fn main(_spawner: Spawner) {
     let trigger = Output::new(p.PC0, Level::Low, Speed::High);
     let echo = ExtiInput::new(p.PC1, p.EXTI1, Pull::Down);
 
-    struct EmbassyClock;
-
-    impl hcsr04_async::Now for EmbassyClock {
-        fn now_micros(&self) -> u64 {
-            Instant::now().as_micros()
-        }
-    }
-
-    let clock = EmbassyClock;
-    let delay = Delay;
     let mut config = usart::Config::default();
     config.baudrate = 115200;
     let mut usart =
         Uart::new(p.USART2, p.PA3, p.PA2, Irqs, p.DMA1_CH7, p.DMA1_CH6, config).unwrap();
 
-    let config = hcsr04_async::Config {
-        distance_unit: DistanceUnit::Centimeters,
-        temperature_unit: TemperatureUnit::Celsius,
+    let config = Hcsr04Config {
+        temperature_c: TEMPERATURE,
+        ..Default::default()
     };
 
-    let mut sensor = Hcsr04::new(trigger, echo, config, clock, delay);
+    let mut sensor = Hcsr04::new(trigger, echo, config);
 
     // The temperature of the environment, if known, can be used to adjust the speed of sound.
     // If unknown, an average estimate must be used.
@@ -77,9 +69,7 @@ async fn main(_spawner: Spawner) {
     let mut ch3 = pwm.split().ch3;
     ch3.enable();
 
-    let max_duty = ch3.max_duty_cycle();
-
-    let mut servo = Servo::new(ch3, 20u8, 180.0, max_duty);
+    let mut servo = Servo::new(ch3, ServoConfig::default());
 
     let mut the_map = [(0, 0.0); FOV / RESOLUTION + 1];
     let mut s: String<2048> = String::new();
@@ -89,14 +79,16 @@ async fn main(_spawner: Spawner) {
             .enumerate()
             .chain((0..FOV).step_by(RESOLUTION).enumerate().rev())
         {
-            let distance = sensor.measure(TEMPERATURE).await;
-            servo.set_angle(angle as f32);
+            // measure only once the sonar points at `angle`
+            servo.move_to(angle as f32, SERVO_SPEED).await;
+            let distance = sensor.try_measure().await;
             //  info!("angle {}", angle);
 
             match distance {
-                Ok(distance) => {
-                    // info!("Distance: {} cm", distance);
-                    the_map[i] = (angle, (distance * 10.0).round() / 10.0);
+                Ok(range) => {
+                    // info!("Distance: {} mm", range.distance_mm);
+                    // in cm with one decimal, as sent before
+                    the_map[i] = (angle, range.distance_mm.round() / 10.0);
                 }
                 Err(e) => {
                     info!("Error: {:?}", e);
@@ -0,0 +1,5 @@
+pub mod motion;
+pub mod odometry;
+
+pub use motion::{Motion, MotionConfig, Progress, SpeedProfile, TimedModel, WithGyro};
+pub use odometry::{Odometry, Pose, PoseChannel};
@@ -0,0 +1,315 @@
+use core::f32::consts::PI;
+
+use embassy_time::{Delay, Duration};
+use embedded_hal_async::delay::DelayNs;
+
+use crate::drive::{DifferentialDrive, DriveGeometry, desaturate};
+use crate::drivers::encoder::{Encoder, WheelGeometry};
+use crate::drivers::motor::DcMotor;
+use crate::error::Error;
+
+/// How far the robot got along its path.
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub struct Travel {
+    /// Distance driven by the middle of the axle, forward positive.
+    pub distance_mm: f32,
+    /// Heading in radians, counter-clockwise positive, not wrapped.
+    pub heading: f32,
+}
+
+/// Measures or estimates the travel of the robot for the motion primitives.
+pub trait Progress {
+    /// Travel from an arbitrary origin, only the difference between two calls matters.
+    fn try_travel(&mut self) -> Result<Travel, Error>;
+
+    /// Wheel speeds commanded for the next `dt` seconds, for estimates without sensors.
+    fn commanded(&mut self, _left_mm_s: f32, _right_mm_s: f32, _dt: f32) {}
+}
+
+/// Heading in radians, counter-clockwise positive and not wrapped, e.g. the integrated yaw of a gyro.
+pub trait HeadingSource {
+    fn try_heading(&mut self) -> Result<f32, Error>;
+}
+
+/// Travel measured by encoders on both wheels.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct EncoderProgress<L: Encoder, R: Encoder> {
+    left: L,
+    right: R,
+    wheel: WheelGeometry,
+    track_width_mm: f32,
+}
+
+impl<L: Encoder, R: Encoder> EncoderProgress<L, R> {
+    pub fn new(left: L, right: R, wheel: WheelGeometry, track_width_mm: f32) -> Self {
+        Self {
+            left,
+            right,
+            wheel,
+            track_width_mm,
+        }
+    }
+}
+
+impl<L: Encoder, R: Encoder> Progress for EncoderProgress<L, R> {
+    fn try_travel(&mut self) -> Result<Travel, Error> {
+        let mm_per_tick = self.wheel.mm_per_tick();
+        let left = self.left.try_ticks().map_err(Into::into)? as f32 * mm_per_tick;
+        let right = self.right.try_ticks().map_err(Into::into)? as f32 * mm_per_tick;
+        Ok(Travel {
+            distance_mm: (left + right) / 2.0,
+            heading: (right - left) / self.track_width_mm,
+        })
+    }
+}
+
+/// Another `Progress` with its heading replaced by a gyro, which does not slip.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct WithGyro<P: Progress, H: HeadingSource> {
+    progress: P,
+    gyro: H,
+}
+
+impl<P: Progress, H: HeadingSource> WithGyro<P, H> {
+    pub fn new(progress: P, gyro: H) -> Self {
+        Self { progress, gyro }
+    }
+}
+
+impl<P: Progress, H: HeadingSource> Progress for WithGyro<P, H> {
+    fn try_travel(&mut self) -> Result<Travel, Error> {
+        Ok(Travel {
+            heading: self.gyro.try_heading()?,
+            ..self.progress.try_travel()?
+        })
+    }
+
+    fn commanded(&mut self, left_mm_s: f32, right_mm_s: f32, dt: f32) {
+        self.progress.commanded(left_mm_s, right_mm_s, dt);
+    }
+}
+
+/// Travel estimated from the commanded wheel speeds alone.
+///
+/// Only as good as the calibration of `DriveGeometry::max_wheel_speed_mm_s`,
+/// which changes with the battery charge and the floor.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct TimedModel {
+    track_width_mm: f32,
+    travel: Travel,
+}
+
+impl TimedModel {
+    pub fn new(geometry: DriveGeometry) -> Self {
+        Self {
+            track_width_mm: geometry.track_width_mm,
+            travel: Travel::default(),
+        }
+    }
+}
+
+impl Progress for TimedModel {
+    fn try_travel(&mut self) -> Result<Travel, Error> {
+        Ok(self.travel)
+    }
+
+    fn commanded(&mut self, left_mm_s: f32, right_mm_s: f32, dt: f32) {
+        self.travel.distance_mm += (left_mm_s + right_mm_s) / 2.0 * dt;
+        self.travel.heading += (right_mm_s - left_mm_s) / self.track_width_mm * dt;
+    }
+}
+
+/// Trapezoidal speed profile of the outer wheel.
+#[derive(Debug, Clone, Copy, defmt::Format, PartialEq)]
+pub struct SpeedProfile {
+    /// Speed to start and finish at, high enough for the motors to turn.
+    pub start_speed_mm_s: f32,
+    pub max_speed_mm_s: f32,
+    pub acceleration_mm_s2: f32,
+}
+
+impl Default for SpeedProfile {
+    fn default() -> Self {
+        Self {
+            start_speed_mm_s: 50.0,
+            max_speed_mm_s: 200.0,
+            acceleration_mm_s2: 400.0,
+        }
+    }
+}
+
+impl SpeedProfile {
+    /// Speed after `done` of `total` mm, accelerating at the start and braking towards the end.
+    pub fn speed_at(&self, done: f32, total: f32) -> f32 {
+        let ramp = done.min(total - done).max(0.0);
+        let start = self.start_speed_mm_s * self.start_speed_mm_s;
+        libm::sqrtf(start + 2.0 * self.acceleration_mm_s2 * ramp).min(self.max_speed_mm_s)
+    }
+
+    /// Time the profile takes over `total` mm.
+    pub fn duration(&self, total: f32) -> Duration {
+        let (start, max, accel) = (
+            self.start_speed_mm_s,
+            self.max_speed_mm_s,
+            self.acceleration_mm_s2,
+        );
+        let ramp = (max * max - start * start) / (2.0 * accel);
+        let seconds = if 2.0 * ramp >= total {
+            let peak = libm::sqrtf(start * start + accel * total);
+            2.0 * (peak - start) / accel
+        } else {
+            2.0 * (max - start) / accel + (total - 2.0 * ramp) / max
+        };
+        Duration::from_micros((seconds * 1_000_000.0) as u64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct MotionConfig {
+    pub profile: SpeedProfile,
+    /// Steering correction in rad/s per radian the heading is off from the plan.
+    pub heading_gain: f32,
+    pub period: Duration,
+    /// The motion ends when the outer wheel is this close to its goal.
+    pub tolerance_mm: f32,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        Self {
+            profile: SpeedProfile::default(),
+            heading_gain: 3.0,
+            period: Duration::from_millis(10),
+            tolerance_mm: 1.0,
+        }
+    }
+}
+
+/// Drives a `DifferentialDrive` along straight lines, arcs and turns in place,
+/// braking at the end of every motion.
+///
+/// A motion taking more than twice its planned time plus a second ends with `Error::Timeout`.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct Motion<P: Progress, D: DelayNs = Delay> {
+    progress: P,
+    config: MotionConfig,
+    delay: D,
+}
+
+impl<P: Progress> Motion<P> {
+    pub fn new(progress: P, config: MotionConfig) -> Self {
+        Self {
+            progress,
+            config,
+            delay: Delay,
+        }
+    }
+}
+
+impl<P: Progress, D: DelayNs> Motion<P, D> {
+    pub fn with_delay<E: DelayNs>(self, delay: E) -> Motion<P, E> {
+        Motion {
+            progress: self.progress,
+            config: self.config,
+            delay,
+        }
+    }
+
+    pub fn progress_mut(&mut self) -> &mut P {
+        &mut self.progress
+    }
+
+    /// Drives straight, negative is backward.
+    pub async fn try_drive_distance<L: DcMotor, R: DcMotor>(
+        &mut self,
+        drive: &mut DifferentialDrive<L, R>,
+        distance_mm: f32,
+    ) -> Result<(), Error> {
+        self.follow(drive, distance_mm, 0.0).await
+    }
+
+    /// Turns in place, counter-clockwise positive.
+    pub async fn try_turn_by<L: DcMotor, R: DcMotor>(
+        &mut self,
+        drive: &mut DifferentialDrive<L, R>,
+        degrees: f32,
+    ) -> Result<(), Error> {
+        self.follow(drive, 0.0, degrees * PI / 180.0).await
+    }
+
+    /// Drives along a circle of `radius_mm` around a point to the side, turning
+    /// by `degrees`: positive to the left, negative to the right. A negative
+    /// radius drives backward.
+    pub async fn try_arc<L: DcMotor, R: DcMotor>(
+        &mut self,
+        drive: &mut DifferentialDrive<L, R>,
+        radius_mm: f32,
+        degrees: f32,
+    ) -> Result<(), Error> {
+        let turn = degrees * PI / 180.0;
+        self.follow(drive, radius_mm * turn.abs(), turn).await
+    }
+
+    async fn follow<L: DcMotor, R: DcMotor>(
+        &mut self,
+        drive: &mut DifferentialDrive<L, R>,
+        distance_mm: f32,
+        turn: f32,
+    ) -> Result<(), Error> {
+        let DriveGeometry {
+            track_width_mm,
+            max_wheel_speed_mm_s,
+        } = drive.geometry();
+        let half_track = track_width_mm / 2.0;
+        let goal = (
+            distance_mm - turn * half_track,
+            distance_mm + turn * half_track,
+        );
+        let outer = goal.0.abs().max(goal.1.abs());
+        if outer <= self.config.tolerance_mm {
+            return Ok(());
+        }
+        let goal_norm = goal.0 * goal.0 + goal.1 * goal.1;
+
+        let config = self.config;
+        let period_us = config.period.as_micros().max(1);
+        let dt = period_us as f32 / 1_000_000.0;
+        let max_steps = (2 * config.profile.duration(outer).as_micros() + 1_000_000) / period_us;
+        let start = self.progress.try_travel()?;
+
+        let result = async {
+            for _ in 0..max_steps {
+                let travel = self.progress.try_travel()?;
+                let distance = travel.distance_mm - start.distance_mm;
+                let heading = travel.heading - start.heading;
+                let wheels = (
+                    distance - heading * half_track,
+                    distance + heading * half_track,
+                );
+                // how far along the planned wheel travel the robot got
+                let done = (wheels.0 * goal.0 + wheels.1 * goal.1) / goal_norm * outer;
+                if done >= outer - config.tolerance_mm {
+                    return Ok(());
+                }
+
+                let speed = config.profile.speed_at(done, outer);
+                let heading_error = turn * done / outer - heading;
+                let correction = config.heading_gain * heading_error * half_track;
+                let left = speed * goal.0 / outer - correction;
+                let right = speed * goal.1 / outer + correction;
+
+                let to_percent = 100.0 / max_wheel_speed_mm_s;
+                let (left, right) = desaturate(left * to_percent, right * to_percent);
+                drive.try_tank(left, right)?;
+                self.progress
+                    .commanded(left / to_percent, right / to_percent, dt);
+                self.delay.delay_us(period_us as u32).await;
+            }
+            Err(Error::Timeout)
+        }
+        .await;
+
+        let stopped = drive.try_brake();
+        result.and(stopped)
+    }
+}
@@ -0,0 +1,131 @@
+use core::f32::consts::PI;
+
+use embassy_sync::pubsub::{DynImmediatePublisher, PubSubChannel};
+
+/// Position on the floor relative to where odometry started, x pointing
+/// forward at the start and y to the left.
+#[derive(Debug, Default, Clone, Copy, defmt::Format, PartialEq)]
+pub struct Pose {
+    pub x_mm: f32,
+    pub y_mm: f32,
+    /// Heading in radians, counter-clockwise positive, within -π..π.
+    pub theta: f32,
+}
+
+/// Keeps only the latest pose, a subscriber that falls behind skips to it.
+pub type PoseChannel<M, const SUBS: usize> = PubSubChannel<M, Pose, 1, SUBS, 1>;
+
+/// Wraps an angle in radians into -π..π.
+pub fn wrap_angle(theta: f32) -> f32 {
+    let wrapped = (theta + PI) % (2.0 * PI);
+    if wrapped < 0.0 {
+        wrapped + PI
+    } else {
+        wrapped - PI
+    }
+}
+
+/// Dead reckoning from the travel of both wheels of a differential drive.
+///
+/// Errors only accumulate: wheel slip and an imprecise track width turn into
+/// heading drift, which a gyro, when there is one, keeps much smaller.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct Odometry {
+    track_width_mm: f32,
+    gyro_weight: f32,
+    pose: Pose,
+    last_travel: Option<(f32, f32)>,
+}
+
+impl Odometry {
+    pub fn new(track_width_mm: f32) -> Self {
+        Self {
+            track_width_mm,
+            gyro_weight: 0.98,
+            pose: Pose::default(),
+            last_travel: None,
+        }
+    }
+
+    /// Share of the heading change taken from the gyro in `update_with_gyro`,
+    /// the rest comes from the wheels.
+    pub fn with_gyro_weight(mut self, weight: f32) -> Self {
+        self.gyro_weight = weight.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn track_width_mm(&self) -> f32 {
+        self.track_width_mm
+    }
+
+    pub fn pose(&self) -> Pose {
+        self.pose
+    }
+
+    pub fn set_pose(&mut self, pose: Pose) {
+        self.pose = pose;
+    }
+
+    /// Back to the origin, the next update only takes the wheel travel as reference.
+    pub fn reset(&mut self) {
+        self.pose = Pose::default();
+        self.last_travel = None;
+    }
+
+    /// Integrates the total distance each wheel has travelled, e.g. `WheelEncoder::distance_mm`.
+    pub fn update(&mut self, left_mm: f32, right_mm: f32) -> Pose {
+        let Some((left, right)) = self.travel_since_last(left_mm, right_mm) else {
+            return self.pose;
+        };
+        let turn = (right - left) / self.track_width_mm;
+        self.integrate((left + right) / 2.0, turn)
+    }
+
+    /// Like `update`, blending the heading change with a gyro yaw rate in rad/s
+    /// measured over the `dt` seconds since the previous update.
+    pub fn update_with_gyro(
+        &mut self,
+        left_mm: f32,
+        right_mm: f32,
+        yaw_rate: f32,
+        dt: f32,
+    ) -> Pose {
+        let Some((left, right)) = self.travel_since_last(left_mm, right_mm) else {
+            return self.pose;
+        };
+        let wheel_turn = (right - left) / self.track_width_mm;
+        let gyro_turn = yaw_rate * dt;
+        let turn = self.gyro_weight * gyro_turn + (1.0 - self.gyro_weight) * wheel_turn;
+        self.integrate((left + right) / 2.0, turn)
+    }
+
+    /// Sends the current pose to every subscriber of a `PoseChannel`.
+    pub fn publish(&self, publisher: &DynImmediatePublisher<'_, Pose>) {
+        publisher.publish_immediate(self.pose);
+    }
+
+    fn travel_since_last(&mut self, left_mm: f32, right_mm: f32) -> Option<(f32, f32)> {
+        let last = self.last_travel.replace((left_mm, right_mm))?;
+        Some((left_mm - last.0, right_mm - last.1))
+    }
+
+    // moves along the arc of length `distance` over which the heading changes by `turn`
+    fn integrate(&mut self, distance: f32, turn: f32) -> Pose {
+        let theta = self.pose.theta;
+        let (dx, dy) = if turn.abs() < 1e-6 {
+            (distance * libm::cosf(theta), distance * libm::sinf(theta))
+        } else {
+            let radius = distance / turn;
+            (
+                radius * (libm::sinf(theta + turn) - libm::sinf(theta)),
+                radius * (libm::cosf(theta) - libm::cosf(theta + turn)),
+            )
+        };
+        self.pose = Pose {
+            x_mm: self.pose.x_mm + dx,
+            y_mm: self.pose.y_mm + dy,
+            theta: wrap_angle(theta + turn),
+        };
+        self.pose
+    }
+}